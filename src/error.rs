@@ -0,0 +1,156 @@
+use sourceimage::Color;
+use wave::ModelError;
+
+use png::{BitDepth, ColorType, DecodingError, EncodingError};
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while loading inputs, building a model or saving its output.
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read or written.
+    Io(io::Error),
+    /// A file isn't a valid PNG image.
+    Decoding(DecodingError),
+    /// An image couldn't be encoded as a PNG.
+    Encoding(EncodingError),
+    /// The PNG uses a color type or bit depth that isn't supported.
+    UnsupportedFormat(ColorType, BitDepth),
+    /// An indexed PNG can't hold a palette with more than 256 colors.
+    PaletteTooLarge(usize),
+    /// An image has a color that its palette doesn't contain.
+    MissingPaletteColor(Color),
+    /// The sample image has no pixels to learn patterns from.
+    EmptySample,
+    /// The pattern size is zero or doesn't fit inside the sample image, given as (rows, columns).
+    InvalidPatternSize(usize, (usize, usize)),
+    /// The output is empty, or without wrapping is smaller than the pattern size, given as
+    /// (rows, columns).
+    InvalidOutputSize(usize, (usize, usize)),
+    /// Frames can't be recorded every 0 collapses.
+    InvalidFrameInterval,
+    /// The tiles or neighbor rules of a tiled model are inconsistent.
+    InvalidTileset(String),
+    /// Pinning the pixel at this (row, column) to the requested colors leaves some part of the
+    /// output without any pattern that fits.
+    ImpossibleConstraint((usize, usize)),
+    /// A constraint image or mask doesn't match the size it should have, both given as
+    /// (rows, columns).
+    ConstraintSize((usize, usize), (usize, usize)),
+    /// A constrained pixel lies outside the output, both given as (rows, columns) with the
+    /// pixel's position first.
+    ConstraintOutOfBounds((usize, usize), (usize, usize)),
+    /// A saved model is truncated, corrupt or from an incompatible version.
+    InvalidSave(String),
+    /// Generation failed.
+    Model(ModelError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Decoding(ref e) => write!(f, "couldn't decode PNG: {}", e),
+            Error::Encoding(ref e) => write!(f, "couldn't encode PNG: {}", e),
+            Error::UnsupportedFormat(color_type, bit_depth) => {
+                write!(f, "unsupported PNG format: {:?} at {:?} bits", color_type, bit_depth)
+            }
+            Error::PaletteTooLarge(size) => {
+                write!(f, "{} colors don't fit in an indexed PNG's palette of 256", size)
+            }
+            Error::MissingPaletteColor(Color(r, g, b, a)) => {
+                write!(f, "the color ({}, {}, {}, {}) isn't in the palette", r, g, b, a)
+            }
+            Error::EmptySample => write!(f, "the sample image is empty"),
+            Error::InvalidPatternSize(size, (rows, columns)) => {
+                write!(f, "a pattern size of {} doesn't fit a {}x{} sample", size, columns, rows)
+            }
+            Error::InvalidOutputSize(size, (rows, columns)) => {
+                write!(f, "a {}x{} output can't hold patterns of size {}", columns, rows, size)
+            }
+            Error::InvalidFrameInterval => {
+                write!(f, "frames must be saved at least once every collapse")
+            }
+            Error::InvalidTileset(ref message) => write!(f, "invalid tileset: {}", message),
+            Error::ImpossibleConstraint((row, column)) => {
+                write!(f, "the pixel at row {}, column {} can't take the required colors",
+                       row,
+                       column)
+            }
+            Error::ConstraintSize((rows, columns), (output_rows, output_columns)) => {
+                write!(f, "a {}x{} constraint image or mask doesn't match the expected {}x{}",
+                       columns,
+                       rows,
+                       output_columns,
+                       output_rows)
+            }
+            Error::ConstraintOutOfBounds((row, column), (rows, columns)) => {
+                write!(f, "the pixel at row {}, column {} is outside the {}x{} output",
+                       row,
+                       column,
+                       columns,
+                       rows)
+            }
+            Error::InvalidSave(ref message) => write!(f, "invalid saved model: {}", message),
+            Error::Model(ref e) => write!(f, "generation failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Decoding(ref e) => Some(e),
+            Error::Encoding(ref e) => Some(e),
+            Error::Model(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<DecodingError> for Error {
+    fn from(e: DecodingError) -> Error {
+        match e {
+            DecodingError::IoError(e) => Error::Io(e),
+            e => Error::Decoding(e),
+        }
+    }
+}
+
+impl From<EncodingError> for Error {
+    fn from(e: EncodingError) -> Error {
+        match e {
+            EncodingError::IoError(e) => Error::Io(e),
+            e => Error::Encoding(e),
+        }
+    }
+}
+
+impl From<ModelError> for Error {
+    fn from(e: ModelError) -> Error {
+        Error::Model(e)
+    }
+}
+
+#[test]
+fn error_display_test() {
+    let e = Error::from(io::Error::new(io::ErrorKind::NotFound, "missing.png"));
+    assert_eq!(e.to_string(), "I/O error: missing.png");
+    assert!(error::Error::source(&e).is_some());
+
+    let e = Error::InvalidPatternSize(4, (3, 5));
+    assert_eq!(e.to_string(), "a pattern size of 4 doesn't fit a 5x3 sample");
+    assert!(error::Error::source(&e).is_none());
+
+    let e = Error::from(ModelError::NoValidStates((1, 2)));
+    assert_eq!(e.to_string(), "generation failed: no valid states left at row 1, column 2");
+}
@@ -0,0 +1,476 @@
+//! Strategies for choosing which cell of a `Wave` to collapse next.
+
+use wave::Wave;
+
+use ndarray::prelude::*;
+use rand::Rng;
+use rand::distributions::{Range, IndependentSample};
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f64;
+
+const NOISE_LEVEL: f64 = 1.;
+
+/// Decides which cell of the wave is collapsed next.
+///
+/// A heuristic is reset whenever the wave starts over, and is told about every cell whose
+/// possible states change after that. It only ever needs to return cells with more than one
+/// state left; any cell it returns that is already decided is an error in the heuristic.
+pub trait Heuristic {
+    /// Called before the first cell is chosen and again after every reset of the wave.
+    fn reset(&mut self, wave: &Wave, rng: &mut dyn Rng);
+
+    /// Called after the possible states of the cell at `position` have changed.
+    fn cell_changed(&mut self, _wave: &Wave, _position: (usize, usize)) {}
+
+    /// Returns the next cell to collapse, or None if every cell has been decided.
+    fn next_cell(&mut self, wave: &Wave, rng: &mut dyn Rng) -> Option<(usize, usize)>;
+
+    /// Returns the random noise drawn by the last reset to break ties, one value per cell, if
+    /// the heuristic uses any. Saved models store it so a loaded copy picks the same cells.
+    fn noise(&self) -> Option<&Array2<f64>> {
+        None
+    }
+
+    /// Called instead of `reset` to start again with `noise` returned by an earlier `noise`
+    /// rather than drawing more. Heuristics without noise simply reset.
+    fn restore(&mut self, wave: &Wave, _noise: Array2<f64>, rng: &mut dyn Rng) {
+        self.reset(wave, rng);
+    }
+}
+
+/// A cell waiting to be collapsed, ordered so that a BinaryHeap pops the lowest score first.
+/// `generation` tells whether the cell has changed since it was queued.
+#[derive(Debug, Copy, Clone)]
+struct Candidate {
+    score: f64,
+    position: (usize, usize),
+    generation: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        other.score
+            .partial_cmp(&self.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.position.cmp(&self.position))
+    }
+}
+
+/// Keeps undecided cells in a heap ordered by a score plus some per-cell noise to break ties.
+/// Cells are queued again whenever they change, and stale entries are skipped when popped.
+/// Staleness is tracked with a per-cell counter rather than by comparing scores, so a cell
+/// whose score is NaN is still returned and the wave can report it.
+struct CandidateHeap {
+    candidates: BinaryHeap<Candidate>,
+    noise: Array2<f64>,
+    generations: Array2<usize>,
+}
+
+impl CandidateHeap {
+    fn new() -> CandidateHeap {
+        CandidateHeap {
+            candidates: BinaryHeap::new(),
+            noise: Array2::zeros((0, 0)),
+            generations: Array2::zeros((0, 0)),
+        }
+    }
+
+    fn reset<F>(&mut self, wave: &Wave, rng: &mut dyn Rng, score: F)
+        where F: Fn(&Wave, (usize, usize)) -> f64
+    {
+        let noise = Array2::from_shape_fn(wave.dim(), |_| rng.next_f64() * NOISE_LEVEL);
+        self.restore(wave, noise, score);
+    }
+
+    fn restore<F>(&mut self, wave: &Wave, noise: Array2<f64>, score: F)
+        where F: Fn(&Wave, (usize, usize)) -> f64
+    {
+        self.noise = noise;
+        self.generations = Array2::zeros(wave.dim());
+        self.candidates.clear();
+        let (y, x) = wave.dim();
+        for position in (0..y * x).map(|i| (i / x, i % x)) {
+            self.cell_changed(wave, position, &score);
+        }
+    }
+
+    fn cell_changed<F>(&mut self, wave: &Wave, position: (usize, usize), score: F)
+        where F: Fn(&Wave, (usize, usize)) -> f64
+    {
+        self.generations[position] += 1;
+        if wave.remaining_states(position) > 1 {
+            self.candidates.push(Candidate {
+                score: score(wave, position) + self.noise[position],
+                position,
+                generation: self.generations[position],
+            });
+        }
+    }
+
+    fn next_cell(&mut self, wave: &Wave) -> Option<(usize, usize)> {
+        while let Some(candidate) = self.candidates.pop() {
+            let position = candidate.position;
+            if wave.remaining_states(position) > 1 &&
+               candidate.generation == self.generations[position] {
+                return Some(position);
+            }
+        }
+        None
+    }
+}
+
+fn entropy_score(wave: &Wave, position: (usize, usize)) -> f64 {
+    wave.entropy(position).unwrap_or(f64::NAN)
+}
+
+fn remaining_score(wave: &Wave, position: (usize, usize)) -> f64 {
+    wave.remaining_states(position) as f64
+}
+
+/// Chooses the cell with the lowest Shannon entropy, with a little noise to break ties.
+pub struct MinEntropy {
+    heap: CandidateHeap,
+}
+
+impl MinEntropy {
+    pub fn new() -> MinEntropy {
+        MinEntropy { heap: CandidateHeap::new() }
+    }
+}
+
+impl Default for MinEntropy {
+    fn default() -> MinEntropy {
+        MinEntropy::new()
+    }
+}
+
+impl Heuristic for MinEntropy {
+    fn reset(&mut self, wave: &Wave, rng: &mut dyn Rng) {
+        self.heap.reset(wave, rng, entropy_score);
+    }
+
+    fn cell_changed(&mut self, wave: &Wave, position: (usize, usize)) {
+        self.heap.cell_changed(wave, position, entropy_score);
+    }
+
+    fn next_cell(&mut self, wave: &Wave, _rng: &mut dyn Rng) -> Option<(usize, usize)> {
+        self.heap.next_cell(wave)
+    }
+
+    fn noise(&self) -> Option<&Array2<f64>> {
+        Some(&self.heap.noise)
+    }
+
+    fn restore(&mut self, wave: &Wave, noise: Array2<f64>, _rng: &mut dyn Rng) {
+        self.heap.restore(wave, noise, entropy_score);
+    }
+}
+
+/// Chooses the cell with the fewest possible states left, ignoring how likely they are.
+pub struct MinimumRemainingValues {
+    heap: CandidateHeap,
+}
+
+impl MinimumRemainingValues {
+    pub fn new() -> MinimumRemainingValues {
+        MinimumRemainingValues { heap: CandidateHeap::new() }
+    }
+}
+
+impl Default for MinimumRemainingValues {
+    fn default() -> MinimumRemainingValues {
+        MinimumRemainingValues::new()
+    }
+}
+
+impl Heuristic for MinimumRemainingValues {
+    fn reset(&mut self, wave: &Wave, rng: &mut dyn Rng) {
+        self.heap.reset(wave, rng, remaining_score);
+    }
+
+    fn cell_changed(&mut self, wave: &Wave, position: (usize, usize)) {
+        self.heap.cell_changed(wave, position, remaining_score);
+    }
+
+    fn next_cell(&mut self, wave: &Wave, _rng: &mut dyn Rng) -> Option<(usize, usize)> {
+        self.heap.next_cell(wave)
+    }
+
+    fn noise(&self) -> Option<&Array2<f64>> {
+        Some(&self.heap.noise)
+    }
+
+    fn restore(&mut self, wave: &Wave, noise: Array2<f64>, _rng: &mut dyn Rng) {
+        self.heap.restore(wave, noise, remaining_score);
+    }
+}
+
+/// Visits cells in a fixed order, choosing the first one that is still undecided.
+struct FixedOrder {
+    order: Vec<(usize, usize)>,
+    rank: Array2<usize>,
+    cursor: usize,
+}
+
+impl FixedOrder {
+    fn new(order: Vec<(usize, usize)>, dims: (usize, usize)) -> FixedOrder {
+        let mut rank = Array2::zeros(dims);
+        for (i, &position) in order.iter().enumerate() {
+            rank[position] = i;
+        }
+        FixedOrder {
+            order,
+            rank,
+            cursor: 0,
+        }
+    }
+
+    fn cell_changed(&mut self, wave: &Wave, position: (usize, usize)) {
+        // Undoing a collapse can make a cell we've already passed undecided again.
+        let rank = self.rank[position];
+        if rank < self.cursor && wave.remaining_states(position) > 1 {
+            self.cursor = rank;
+        }
+    }
+
+    fn next_cell(&mut self, wave: &Wave) -> Option<(usize, usize)> {
+        while self.cursor < self.order.len() {
+            let position = self.order[self.cursor];
+            if wave.remaining_states(position) > 1 {
+                return Some(position);
+            }
+            self.cursor += 1;
+        }
+        None
+    }
+}
+
+/// Collapses cells row by row, from the top left to the bottom right.
+pub struct Scanline {
+    order: Option<FixedOrder>,
+}
+
+impl Scanline {
+    pub fn new() -> Scanline {
+        Scanline { order: None }
+    }
+}
+
+impl Default for Scanline {
+    fn default() -> Scanline {
+        Scanline::new()
+    }
+}
+
+impl Heuristic for Scanline {
+    fn reset(&mut self, wave: &Wave, _rng: &mut dyn Rng) {
+        let (y, x) = wave.dim();
+        let order = (0..y * x).map(|i| (i / x, i % x)).collect();
+        self.order = Some(FixedOrder::new(order, (y, x)));
+    }
+
+    fn cell_changed(&mut self, wave: &Wave, position: (usize, usize)) {
+        if let Some(ref mut order) = self.order {
+            order.cell_changed(wave, position);
+        }
+    }
+
+    fn next_cell(&mut self, wave: &Wave, _rng: &mut dyn Rng) -> Option<(usize, usize)> {
+        self.order.as_mut().and_then(|o| o.next_cell(wave))
+    }
+}
+
+/// Collapses cells in a spiral, starting at the center and working outwards ring by ring.
+pub struct Spiral {
+    order: Option<FixedOrder>,
+}
+
+impl Spiral {
+    pub fn new() -> Spiral {
+        Spiral { order: None }
+    }
+}
+
+impl Default for Spiral {
+    fn default() -> Spiral {
+        Spiral::new()
+    }
+}
+
+impl Heuristic for Spiral {
+    fn reset(&mut self, wave: &Wave, _rng: &mut dyn Rng) {
+        let (y, x) = wave.dim();
+        let center = ((y as f64 - 1.) / 2., (x as f64 - 1.) / 2.);
+        let key = |&(i, j): &(usize, usize)| {
+            let (dy, dx) = (i as f64 - center.0, j as f64 - center.1);
+            (dy.abs().max(dx.abs()), dy.atan2(dx))
+        };
+        let mut order: Vec<(usize, usize)> = (0..y * x).map(|i| (i / x, i % x)).collect();
+        order.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+        self.order = Some(FixedOrder::new(order, (y, x)));
+    }
+
+    fn cell_changed(&mut self, wave: &Wave, position: (usize, usize)) {
+        if let Some(ref mut order) = self.order {
+            order.cell_changed(wave, position);
+        }
+    }
+
+    fn next_cell(&mut self, wave: &Wave, _rng: &mut dyn Rng) -> Option<(usize, usize)> {
+        self.order.as_mut().and_then(|o| o.next_cell(wave))
+    }
+}
+
+/// Chooses uniformly at random among the cells that are still undecided.
+pub struct Random {
+    undecided: Vec<(usize, usize)>,
+    listed: Array2<bool>,
+}
+
+impl Random {
+    pub fn new() -> Random {
+        Random {
+            undecided: Vec::new(),
+            listed: Array2::from_elem((0, 0), false),
+        }
+    }
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new()
+    }
+}
+
+impl Heuristic for Random {
+    fn reset(&mut self, wave: &Wave, _rng: &mut dyn Rng) {
+        let (y, x) = wave.dim();
+        self.undecided = (0..y * x).map(|i| (i / x, i % x)).collect();
+        self.listed = Array2::from_elem((y, x), true);
+    }
+
+    fn cell_changed(&mut self, wave: &Wave, position: (usize, usize)) {
+        if !self.listed[position] && wave.remaining_states(position) > 1 {
+            self.listed[position] = true;
+            self.undecided.push(position);
+        }
+    }
+
+    fn next_cell(&mut self, wave: &Wave, rng: &mut dyn Rng) -> Option<(usize, usize)> {
+        let mut rng = rng;
+        while !self.undecided.is_empty() {
+            let index = Range::new(0, self.undecided.len() as u64).ind_sample(&mut rng) as usize;
+            let position = self.undecided[index];
+            if wave.remaining_states(position) > 1 {
+                return Some(position);
+            }
+            // Decided cells are dropped lazily.
+            self.undecided.swap_remove(index);
+            self.listed[position] = false;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+use wave::WrappingType;
+#[cfg(test)]
+use utils::seeded_rng;
+
+#[cfg(test)]
+fn unconstrained_wave(dims: (usize, usize), num_states: usize) -> Wave {
+    let propagator = vec![vec![(0..num_states).collect(); num_states]; 4];
+    Wave::new(dims, vec![1; num_states], propagator, WrappingType::NoWrap)
+}
+
+#[test]
+fn min_entropy_test() {
+    let mut wave = unconstrained_wave((3, 3), 40);
+    let mut rng = seeded_rng(0);
+    let mut heuristic = MinEntropy::new();
+    heuristic.reset(&wave, &mut rng);
+    // Leaves an entropy of ln(2) against ln(40) elsewhere, a bigger gap than the noise.
+    for state in 0..38 {
+        wave.ban((2, 1), state);
+    }
+    heuristic.cell_changed(&wave, (2, 1));
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), Some((2, 1)));
+}
+
+#[test]
+fn nan_score_test() {
+    let wave = unconstrained_wave((2, 2), 3);
+    let mut heap = CandidateHeap::new();
+    heap.reset(&wave, &mut seeded_rng(0), |_, _| f64::NAN);
+    assert!(heap.next_cell(&wave).is_some());
+}
+
+#[test]
+fn minimum_remaining_values_test() {
+    let mut wave = unconstrained_wave((3, 3), 5);
+    let mut rng = seeded_rng(0);
+    let mut heuristic = MinimumRemainingValues::new();
+    heuristic.reset(&wave, &mut rng);
+    wave.ban((1, 2), 0);
+    wave.ban((1, 2), 1);
+    heuristic.cell_changed(&wave, (1, 2));
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), Some((1, 2)));
+}
+
+#[test]
+fn scanline_test() {
+    let mut wave = unconstrained_wave((2, 3), 2);
+    let mut rng = seeded_rng(0);
+    let mut heuristic = Scanline::new();
+    heuristic.reset(&wave, &mut rng);
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), Some((0, 0)));
+    wave.ban((0, 0), 0);
+    wave.ban((0, 1), 0);
+    heuristic.cell_changed(&wave, (0, 0));
+    heuristic.cell_changed(&wave, (0, 1));
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), Some((0, 2)));
+}
+
+#[test]
+fn spiral_test() {
+    let mut wave = unconstrained_wave((5, 5), 2);
+    let mut rng = seeded_rng(0);
+    let mut heuristic = Spiral::new();
+    heuristic.reset(&wave, &mut rng);
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), Some((2, 2)));
+    wave.ban((2, 2), 0);
+    heuristic.cell_changed(&wave, (2, 2));
+    let next = heuristic.next_cell(&wave, &mut rng).unwrap();
+    assert!((next.0 as isize - 2).abs() <= 1 && (next.1 as isize - 2).abs() <= 1);
+}
+
+#[test]
+fn random_test() {
+    let mut wave = unconstrained_wave((3, 3), 2);
+    let mut rng = seeded_rng(0);
+    let mut heuristic = Random::new();
+    heuristic.reset(&wave, &mut rng);
+    for i in 0..8 {
+        wave.ban((i / 3, i % 3), 0);
+        heuristic.cell_changed(&wave, (i / 3, i % 3));
+    }
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), Some((2, 2)));
+    wave.ban((2, 2), 0);
+    assert_eq!(heuristic.next_cell(&wave, &mut rng), None);
+}
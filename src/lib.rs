@@ -0,0 +1,44 @@
+//! Procedural image generation with the Wave Function Collapse algorithm.
+//!
+//! An `OverlappingModel` learns the small patterns in a seed image and generates a larger image
+//! made only of those patterns. A `TiledModel` fills a grid with hand-authored tiles instead,
+//! following explicit rules about which tiles may sit next to each other.
+//!
+//! ```no_run
+//! # fn generate() -> Result<(), rust_wfc::Error> {
+//! use rust_wfc::{OverlappingModel, SeedImage, Symmetry, WrappingType};
+//!
+//! let seed_image = SeedImage::from_file("assets/Knot.png")?;
+//! let model = OverlappingModel::from_seed_image(seed_image, (50, 50), 3,
+//!                                               WrappingType::NoWrap,
+//!                                               WrappingType::Torus,
+//!                                               &Symmetry::all())?;
+//! model.reseed(42);
+//! model.collapse_and_propagate_with_restarts(5, 20, 200)?;
+//! let pixels = model.pixels();
+//! println!("Generated a {:?} image", pixels.dim());
+//! # Ok(())
+//! # }
+//! ```
+
+extern crate bit_vec;
+extern crate png;
+extern crate ndarray;
+extern crate rand;
+
+mod error;
+pub mod heuristic;
+mod listener;
+mod overlappingmodel;
+mod sourceimage;
+mod tiledmodel;
+mod utils;
+mod wave;
+
+pub use error::Error;
+pub use listener::Listener;
+pub use overlappingmodel::OverlappingModel;
+pub use sourceimage::{Color, SeedImage, save_image, save_indexed_image};
+pub use tiledmodel::{Adjacency, NeighborRule, Tile, TileSymmetry, TiledModel};
+pub use utils::{Symmetry, Transform};
+pub use wave::{ModelError, Step, Wave, WrappingType};
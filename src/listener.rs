@@ -0,0 +1,59 @@
+use wave::ModelError;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Receives events from a `Wave` while it generates, for example to show progress or collect
+/// statistics. Every method does nothing by default, so implementors only override the events
+/// they care about.
+///
+/// A wave owns its listener, so to read back what a listener collected after generation wrap
+/// it in an `Rc<RefCell<_>>`, which implements this trait too, and keep a clone.
+pub trait Listener {
+    /// A cell was picked to be collapsed next.
+    fn cell_chosen(&mut self, _position: (usize, usize)) {}
+
+    /// A cell was collapsed to `state`. The other states it loses are reported separately.
+    fn cell_collapsed(&mut self, _position: (usize, usize), _state: usize) {}
+
+    /// A state is no longer possible in a cell, whether through a collapse, propagation or a
+    /// reset of the wave.
+    fn state_removed(&mut self, _position: (usize, usize), _state: usize) {}
+
+    /// A previously removed state became possible again because a collapse was undone while
+    /// backtracking.
+    fn state_restored(&mut self, _position: (usize, usize), _state: usize) {}
+
+    /// A cell was left without any possible states.
+    fn contradiction(&mut self, _position: (usize, usize)) {}
+
+    /// A run of collapse_and_propagate ended, or a step found nothing left to collapse, either
+    /// with every cell decided or with an error.
+    fn finished(&mut self, _result: &Result<(), ModelError>) {}
+}
+
+impl<L: Listener> Listener for Rc<RefCell<L>> {
+    fn cell_chosen(&mut self, position: (usize, usize)) {
+        self.borrow_mut().cell_chosen(position);
+    }
+
+    fn cell_collapsed(&mut self, position: (usize, usize), state: usize) {
+        self.borrow_mut().cell_collapsed(position, state);
+    }
+
+    fn state_removed(&mut self, position: (usize, usize), state: usize) {
+        self.borrow_mut().state_removed(position, state);
+    }
+
+    fn state_restored(&mut self, position: (usize, usize), state: usize) {
+        self.borrow_mut().state_restored(position, state);
+    }
+
+    fn contradiction(&mut self, position: (usize, usize)) {
+        self.borrow_mut().contradiction(position);
+    }
+
+    fn finished(&mut self, result: &Result<(), ModelError>) {
+        self.borrow_mut().finished(result);
+    }
+}
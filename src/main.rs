@@ -1,46 +1,285 @@
-#![allow(dead_code)]
-#![feature(try_from)]
+extern crate rust_wfc;
 
+use rust_wfc::{Error, OverlappingModel, SeedImage, Symmetry, WrappingType};
+use std::env;
+use std::path::Path;
+use std::process;
 
-extern crate bit_vec;
-extern crate png;
-extern crate ndarray;
-extern crate rand;
-extern crate chrono;
+const MAX_BACKTRACK_DEPTH: usize = 20;
+const MAX_BACKTRACKS: usize = 200;
 
-mod overlappingmodel;
-mod sourceimage;
-mod utils;
+static USAGE: &str = "Usage: rust-wfc [options] <sample.png>
 
-use chrono::prelude::*;
-use std::path::Path;
-use std::fs::create_dir;
+Generates images from the patterns in a sample image.
 
-static INPUT: &'static str = "./assets/Knot.png";
-static OUTPUT_DIR: &'static str = "./output";
+Options:
+    -o, --output PATH           Where to write the image (default: output.png). With
+                                --count above 1 the images are numbered, as in output-1.png.
+    --width N                   Output width in pixels (default: 50)
+    --height N                  Output height in pixels (default: 50)
+    -n, --pattern-size N        Size of the square patterns learned from the sample (default: 3)
+    --symmetry N                Learn each pattern in 1, 2, 4 or 8 orientations (default: 8)
+    --periodic-input BOOL       Learn patterns that wrap around the sample's edges (default: true)
+    --periodic-output BOOL      Make the output tile seamlessly (default: false)
+    --indexed BOOL              Write an indexed PNG with the sample's palette (default: false)
+    --seed N                    Seed for the random number generator
+    -c, --count N               Number of images to generate (default: 1)
+    -a, --attempts N            Restarts allowed per image before giving up (default: 5)
+    --record K                  Also save a frame every K collapses next to each image, as
+                                output-frame-00000.png onwards. Recording disables restarts.
+    --help                      Show this message";
 
+/// Everything the command line can configure.
+#[derive(Debug, PartialEq)]
+struct Options {
+    sample: String,
+    output: String,
+    width: usize,
+    height: usize,
+    pattern_size: usize,
+    symmetry: usize,
+    periodic_input: bool,
+    periodic_output: bool,
+    indexed: bool,
+    seed: Option<u64>,
+    count: usize,
+    attempts: usize,
+    record: Option<usize>,
+}
 
-fn main() {
-    // Make sure output folder exists
-    if !(Path::new(OUTPUT_DIR).is_dir()) {
-        match create_dir(OUTPUT_DIR) {
-            Err(_) => panic!("Don't have permission to make files here"),
-            Ok(_)  => (),
-        };
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            sample: String::new(),
+            output: "output.png".to_owned(),
+            width: 50,
+            height: 50,
+            pattern_size: 3,
+            symmetry: 8,
+            periodic_input: true,
+            periodic_output: false,
+            indexed: false,
+            seed: None,
+            count: 1,
+            attempts: 5,
+            record: None,
+        }
     }
+}
 
-    let im = sourceimage::SeedImage::from_file(INPUT);
-    let model = overlappingmodel::OverlappingModel::from_seed_image(im, (50, 50), 3);
+/// Returns `Ok(None)` when the user only asked for help.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    fn value<T: std::str::FromStr>(flag: &str, arg: Option<String>) -> Result<T, String> {
+        let arg = arg.ok_or_else(|| format!("{} needs a value", flag))?;
+        arg.parse().map_err(|_| format!("{} is not a valid value for {}", arg, flag))
+    }
 
-    match model.collapse_and_propagate() {
-        Ok(_) => {
-            let now: i64 = Local::now().timestamp();
-            model.to_image(&format!("{}/output{}.png", OUTPUT_DIR, now))
+    let mut options = Options::default();
+    let mut sample = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" => return Ok(None),
+            "-o" | "--output" => options.output = value(&arg, args.next())?,
+            "--width" => options.width = value(&arg, args.next())?,
+            "--height" => options.height = value(&arg, args.next())?,
+            "-n" | "--pattern-size" => options.pattern_size = value(&arg, args.next())?,
+            "--symmetry" => options.symmetry = value(&arg, args.next())?,
+            "--periodic-input" => options.periodic_input = value(&arg, args.next())?,
+            "--periodic-output" => options.periodic_output = value(&arg, args.next())?,
+            "--indexed" => options.indexed = value(&arg, args.next())?,
+            "--seed" => options.seed = Some(value(&arg, args.next())?),
+            "-c" | "--count" => options.count = value(&arg, args.next())?,
+            "-a" | "--attempts" => options.attempts = value(&arg, args.next())?,
+            "--record" => options.record = Some(value(&arg, args.next())?),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if sample.is_none() => sample = Some(arg),
+            _ => return Err(format!("Unexpected argument {}", arg)),
         }
-        Err(u) => {
-            println!("{:?}", u);
-            let now: i64 = Local::now().timestamp();
-            model.to_image(&format!("{}/output{}.png", OUTPUT_DIR, now));
+    }
+
+    options.sample = sample.ok_or_else(|| "No sample image given".to_owned())?;
+    if Symmetry::from_count(options.symmetry).is_none() {
+        return Err(format!("--symmetry must be 1, 2, 4 or 8, not {}", options.symmetry));
+    }
+    if options.width == 0 || options.height == 0 || options.pattern_size == 0 {
+        return Err("The output size and pattern size must be positive".to_owned());
+    }
+    if options.attempts == 0 {
+        return Err("--attempts must be at least 1".to_owned());
+    }
+    if options.record == Some(0) {
+        return Err("--record must be at least 1".to_owned());
+    }
+    Ok(Some(options))
+}
+
+fn output_path(output: &str, index: usize, count: usize) -> String {
+    //! Numbers the output files when more than one image is generated.
+    if count == 1 {
+        return output.to_owned();
+    }
+    let path = Path::new(output);
+    let stem = path.file_stem().map_or("output".into(), |s| s.to_string_lossy());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, index + 1, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, index + 1),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+fn frame_prefix(output: &str) -> String {
+    //! Frames are named after the image they belong to, without its extension.
+    let path = Path::new(output);
+    let stem = path.file_stem().map_or("output".into(), |s| s.to_string_lossy());
+    path.with_file_name(format!("{}-frame-", stem)).to_string_lossy().into_owned()
+}
+
+fn run(options: &Options) -> Result<bool, Error> {
+    //! Generates every requested image, returning whether all of them succeeded. Failed
+    //! images are still written so the contradiction can be inspected.
+    let wrap = |periodic| if periodic { WrappingType::Torus } else { WrappingType::NoWrap };
+    let model = OverlappingModel::from_seed_image(SeedImage::from_file(&options.sample)?,
+                                                  (options.width, options.height),
+                                                  options.pattern_size,
+                                                  wrap(options.periodic_output),
+                                                  wrap(options.periodic_input),
+                                                  &Symmetry::from_count(options.symmetry)
+                                                      .unwrap())?;
+
+    let mut succeeded = true;
+    for i in 0..options.count {
+        if let Some(seed) = options.seed {
+            model.reseed(seed.wrapping_add(i as u64));
+        }
+        model.reset();
+        let path = output_path(&options.output, i, options.count);
+        let result = match options.record {
+            Some(frame_every) => {
+                model.collapse_and_propagate_recording(MAX_BACKTRACK_DEPTH,
+                                                       MAX_BACKTRACKS,
+                                                       frame_every,
+                                                       &frame_prefix(&path))
+                    .map(|_| 1)
+            }
+            None => {
+                model.collapse_and_propagate_with_restarts(options.attempts,
+                                                           MAX_BACKTRACK_DEPTH,
+                                                           MAX_BACKTRACKS)
+                    .map_err(Error::from)
+            }
+        };
+        match result {
+            Ok(attempt) => println!("Wrote {} on attempt {}", path, attempt),
+            Err(Error::Model(e)) => {
+                eprintln!("Failed to generate {}: {}", path, e);
+                succeeded = false;
+            }
+            Err(e) => return Err(e),
+        }
+        if options.indexed {
+            model.to_indexed_image(&path)?;
+        } else {
+            model.to_image(&path)?;
         }
     }
+    Ok(succeeded)
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match run(&options) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+fn args(line: &str) -> std::vec::IntoIter<String> {
+    line.split_whitespace().map(String::from).collect::<Vec<_>>().into_iter()
+}
+
+#[test]
+fn parse_args_test() {
+    let options = parse_args(args("assets/Knot.png")).unwrap().unwrap();
+    assert_eq!(options,
+               Options { sample: "assets/Knot.png".to_owned(), ..Options::default() });
+
+    let options = parse_args(args("--width 20 --height 10 -n 2 --symmetry 4 --seed 7 \
+                                   --periodic-input false --periodic-output true \
+                                   --indexed true -c 3 -a 1 --record 10 \
+                                   -o out/knot.png assets/Knot.png"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(options,
+               Options {
+                   sample: "assets/Knot.png".to_owned(),
+                   output: "out/knot.png".to_owned(),
+                   width: 20,
+                   height: 10,
+                   pattern_size: 2,
+                   symmetry: 4,
+                   periodic_input: false,
+                   periodic_output: true,
+                   indexed: true,
+                   seed: Some(7),
+                   count: 3,
+                   attempts: 1,
+                   record: Some(10),
+               });
+
+    assert_eq!(parse_args(args("--help")).unwrap(), None);
+    assert!(parse_args(args("")).is_err());
+    assert!(parse_args(args("--symmetry 3 a.png")).is_err());
+    assert!(parse_args(args("--width a.png")).is_err());
+    assert!(parse_args(args("--frobnicate a.png")).is_err());
+    assert!(parse_args(args("--record 0 a.png")).is_err());
+}
+
+#[test]
+fn output_path_test() {
+    assert_eq!(output_path("out/knot.png", 0, 1), "out/knot.png");
+    assert_eq!(output_path("out/knot.png", 0, 2), "out/knot-1.png");
+    assert_eq!(output_path("knot", 1, 2), "knot-2");
+    assert_eq!(frame_prefix("out/knot-2.png"), "out/knot-2-frame-");
+}
+
+#[test]
+fn run_count_test() {
+    use std::fs;
+
+    let directory = env::temp_dir().join("rust-wfc-run-count-test");
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    let output = |name: &str| directory.join(name).to_string_lossy().into_owned();
+    let options = Options {
+        sample: "assets/Knot.png".to_owned(),
+        output: output("knot.png"),
+        width: 20,
+        height: 20,
+        seed: Some(1),
+        count: 2,
+        ..Options::default()
+    };
+    assert!(run(&options).unwrap());
+    assert!(run(&Options { output: output("single.png"), seed: Some(2), count: 1, ..options })
+        .unwrap());
+
+    let read = |name: &str| fs::read(output(name)).unwrap();
+    assert!(read("knot-1.png") != read("knot-2.png"));
+    assert_eq!(read("knot-2.png"), read("single.png"));
 }
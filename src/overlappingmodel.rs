@@ -2,424 +2,678 @@
 
 
 use utils::*;
+pub use wave::{ModelError, Step, WrappingType};
 
 use bit_vec::BitVec;
-use sourceimage::{Color, SeedImage};
-use png::{Encoder, ColorType, BitDepth, HasParameters};
+use error::Error;
+use heuristic::Heuristic;
+use listener::Listener;
+use sourceimage::{Color, SeedImage, save_image, save_indexed_image};
+use wave::{Wave, DIRECTIONS};
 use ndarray::prelude::*;
-use rand;
+use rand::{self, SeedableRng, XorShiftRng};
 
 use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::cell::RefCell;
-use std::{f64, usize};
 use std::hash::Hash;
-use std::convert::TryInto;
-use std::collections::HashSet;
-use std::fs::File;
-use std::path::Path;
-use std::io::BufWriter;
 
-const NOISE_LEVEL: f64 = 1.;
 
-#[derive(Debug, Copy, Clone)]
-pub enum ModelError {
-    NoValidStates((usize, usize)),
-    UnexpectedNaN((usize, usize)),
-    AllStatesDecided,
-}
-
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
-enum WrappingType {
-    NoWrap,
-    Torus,
+/// Generates images made of the overlapping N by N patterns found in a seed image.
+pub struct OverlappingModel {
+    wave: RefCell<Wave>,
+    palette: Vec<Color>,
+    output_palette: Vec<Color>,
+    states: Vec<(Array2<Color>, usize)>,
+    indexed_states: Vec<Array2<usize>>,
+    state_size: usize,
+    output_dim: (usize, usize),
+    rng: RefCell<XorShiftRng>,
 }
 
-#[derive(Debug)]
-struct UncertainCell {
-    possible_colors: RefCell<BitVec>,
-    possible_states: RefCell<BitVec>,
-}
+impl OverlappingModel {
+    pub fn from_seed_image(seed_image: SeedImage,
+                           output_dims: (usize, usize),
+                           block_size: usize,
+                           output_wrap: WrappingType,
+                           input_wrap: WrappingType,
+                           symmetry: &Symmetry)
+                           -> Result<OverlappingModel, Error> {
+        //! Builds a model from the patterns in `seed_image`. `output_wrap` controls whether the
+        //! generated image tiles seamlessly, while `input_wrap` controls whether patterns that
+        //! cross the edges of the seed image are learned as well. Every pattern is also learned
+        //! in each of the orientations in `symmetry`. Without wrapping, every pattern placed in
+        //! the output lies wholly inside it, so the output must be at least `block_size` pixels
+        //! in each direction.
+        let (rows, columns) = seed_image.image_data.dim();
+        if rows == 0 || columns == 0 {
+            return Err(Error::EmptySample);
+        }
+        if block_size == 0 || block_size > rows || block_size > columns {
+            return Err(Error::InvalidPatternSize(block_size, (rows, columns)));
+        }
 
-impl UncertainCell {
-    pub fn new(num_colors: usize, num_states: usize) -> UncertainCell {
-        let possible_colors = RefCell::new(BitVec::from_elem(num_colors, true));
-        let possible_states = RefCell::new(BitVec::from_elem(num_states, true));
-        UncertainCell {
-            possible_colors: possible_colors,
-            possible_states: possible_states,
+        let palette = OverlappingModel::build_color_palette(&seed_image.image_data);
+        let output_palette = seed_image.palette.clone().unwrap_or_else(|| palette.clone());
+        let states = OverlappingModel::build_augmented_block_frequency_map(&seed_image.image_data,
+                                                                           block_size,
+                                                                           input_wrap,
+                                                                           symmetry);
+        if states.is_empty() {
+            return Err(Error::EmptySample);
         }
+        OverlappingModel::from_states(palette,
+                                      output_palette,
+                                      states,
+                                      block_size,
+                                      output_dims,
+                                      output_wrap)
     }
 
-    pub fn entropy<T>(&self, concrete_states: &[(T, usize)]) -> Option<f64> {
-        let possible_states = self.possible_states.borrow();
-        debug_assert_eq!(possible_states.len(), concrete_states.len());
-
-        if possible_states.none() {
-            return None;
-        };
-        if possible_states.iter().filter(|p| *p).count() == 1 {
-            return Some(0.);
-        };
-
-        // Counts the number of possible states permitted by the UncertainCell
-        let possible_state_count: usize = concrete_states.iter()
-            .map(|&(_, count)| count)
-            .zip(possible_states.iter())
-            .filter(|&(_, p)| p)
-            .map(|(count, _)| count)
-            .sum();
+    pub fn for_inpainting(image: &SeedImage,
+                          mask: &Array2<bool>,
+                          block_size: usize,
+                          symmetry: &Symmetry)
+                          -> Result<OverlappingModel, Error> {
+        //! Builds a model the size of `image` that learns its patterns from the parts of the
+        //! image that are kept, ready for `inpaint`. Pixels where `mask` is true are missing, so
+        //! no pattern touching them is learned. Returns EmptySample if every pattern touches a
+        //! missing pixel.
+        let (rows, columns) = image.image_data.dim();
+        if mask.dim() != (rows, columns) {
+            return Err(Error::ConstraintSize(mask.dim(), (rows, columns)));
+        }
+        if block_size == 0 || block_size > rows || block_size > columns {
+            return Err(Error::InvalidPatternSize(block_size, (rows, columns)));
+        }
 
-        let possible_state_count = possible_state_count as f64;
-        let entropy: f64 = concrete_states.iter()
-            .map(|&(_, count)| count)
-            .zip(possible_states.iter())
-            .filter(|&(_, p)| p)
-            .map(|(count, _)| {
-                let x = count as f64 / possible_state_count;
-                x * x.ln()
-            })
-            .sum();
+        let mut states = Vec::<(Array2<Color>, usize)>::new();
+        let mut state_indices = HashMap::<Array2<Color>, usize>::new();
+        let blocks = OverlappingModel::image_blocks(&image.image_data,
+                                                    block_size,
+                                                    WrappingType::NoWrap);
+        let mask_blocks = OverlappingModel::image_blocks(mask, block_size, WrappingType::NoWrap);
+        for (block, missing) in blocks.into_iter().zip(mask_blocks) {
+            if missing.iter().any(|&m| m) {
+                continue;
+            }
+            for b in symmetry.apply(&block) {
+                OverlappingModel::count_block(&mut states, &mut state_indices, b);
+            }
+        }
+        if states.is_empty() {
+            return Err(Error::EmptySample);
+        }
 
-        Some(-entropy)
+        let palette = OverlappingModel::build_color_palette(&image.image_data);
+        let output_palette = image.palette.clone().unwrap_or_else(|| palette.clone());
+        OverlappingModel::from_states(palette,
+                                      output_palette,
+                                      states,
+                                      block_size,
+                                      (columns, rows),
+                                      WrappingType::NoWrap)
+    }
 
+    fn from_states(palette: Vec<Color>,
+                   output_palette: Vec<Color>,
+                   states: Vec<(Array2<Color>, usize)>,
+                   block_size: usize,
+                   output_dims: (usize, usize),
+                   output_wrap: WrappingType)
+                   -> Result<OverlappingModel, Error> {
+        let wave_dim = wave_dim(output_dims, block_size, output_wrap)?;
+        let indexed_states: Vec<Array2<usize>> = states.iter()
+            .map(|(s, _)| s.map(|c| palette.binary_search(c).expect("Color not found in palette!")))
+            .collect();
+        let propagator = OverlappingModel::build_propagator(&indexed_states);
+        let weights = states.iter().map(|&(_, count)| count).collect();
+        let wave = Wave::new(wave_dim, weights, propagator, output_wrap);
+
+        Ok(OverlappingModel {
+            wave: RefCell::new(wave),
+            palette,
+            output_palette,
+            states,
+            indexed_states,
+            state_size: block_size,
+            output_dim: (output_dims.1, output_dims.0),
+            rng: RefCell::new(rand::weak_rng()),
+        })
     }
 
-    pub fn collapse<T>(&self, concrete_states: &[(T, usize)]) {
-        /// Marks all but a single state of the BitVec as forbidden, randomly chosen
-        /// from the states still permitted and weighted by their frequency in the original image.
-        let mut possible_states = self.possible_states.borrow_mut();
-        let chosen_state = masked_weighted_choice(concrete_states, &*possible_states).unwrap();
-        possible_states.clear();
-        possible_states.set(chosen_state, true);
+    pub fn reseed(&self, seed: u64) {
+        //! Replaces the model's random number generator with one seeded from `seed`. Generating
+        //! from the same seed image with the same parameters and seed gives identical output.
+        *self.rng.borrow_mut() = seeded_rng(seed);
     }
 
-    pub fn consistent(&self) -> bool {
-        //! Returns true if any states are permitted.
-        self.possible_colors.borrow().any()
+    pub fn set_rng(&self, rng: XorShiftRng) {
+        //! Replaces the model's random number generator with a caller-supplied one. Only
+        //! XorShiftRng is accepted so that `save` can store its exact state.
+        *self.rng.borrow_mut() = rng;
     }
 
-    pub fn to_color(&self, palette: &[Color]) -> Color {
-        //! Returns the average color of all remaining possible colors.
-        if !self.consistent() { return Color(255, 0, 128);}
-        let mut r = 0usize;
-        let mut g = 0usize;
-        let mut b = 0usize;
-        let mut count = 0usize;
-        let colors = self.possible_colors.borrow();
+    pub fn set_heuristic<H: Heuristic + 'static>(&self, heuristic: H) {
+        //! Replaces the heuristic used to choose which cell to collapse next. By default the
+        //! cell with the lowest entropy is chosen, see heuristic::MinEntropy.
+        self.wave.borrow_mut().set_heuristic(Box::new(heuristic));
+    }
 
-        for (index, c) in palette.iter().enumerate() {
-            if colors.get(index).unwrap() {
-                count += 1;
-                r += c.0 as usize;
-                g += c.1 as usize;
-                b += c.2 as usize;
-            }
-        }
+    pub fn set_listener<L: Listener + 'static>(&self, listener: L) {
+        //! Replaces the listener that is told about progress during generation.
+        self.wave.borrow_mut().set_listener(Box::new(listener));
+    }
 
-        Color((r / count) as u8, (g / count) as u8, (b / count) as u8)
+    pub fn to_image(&self, file_path: &str) -> Result<(), Error> {
+        //! Writes the image returned by `pixels` to a PNG file at `file_path`.
+        save_image(&self.pixels(), file_path)
     }
-}
 
+    pub fn step(&self) -> Result<Step, ModelError> {
+        //! Collapses a single cell and propagates the result, so generation can be paused and
+        //! the output inspected between steps. Calling this until it returns Finished or
+        //! Contradiction is equivalent to collapse_and_propagate.
+        let mut rng = self.rng.borrow_mut();
+        self.wave.borrow_mut().step(&mut *rng)
+    }
 
-pub struct OverlappingModel {
-    model: Array2<UncertainCell>,
-    palette: Vec<Color>,
-    states: Vec<(Array2<Color>, usize)>,
-    state_size: usize,
-    wrap: WrappingType,
-    color_changes: RefCell<HashSet<(usize, usize)>>,
-    state_changes: RefCell<HashSet<(usize, usize)>>,
-}
+    pub fn collapse_and_propagate(&self) -> Result<(), ModelError> {
+        let mut rng = self.rng.borrow_mut();
+        self.wave.borrow_mut().collapse_and_propagate(&mut *rng)
+    }
 
-impl OverlappingModel {
-    pub fn from_seed_image(seed_image: SeedImage,
-                           output_dims: (usize, usize),
-                           block_size: usize)
-                           -> OverlappingModel {
-        let palette = OverlappingModel::build_color_palette(&seed_image.image_data);
-        let states = OverlappingModel::build_augmented_block_frequency_map(&seed_image.image_data,
-                                                                           block_size);
+    pub fn collapse_and_propagate_with_backtracking(&self,
+                                                    max_depth: usize,
+                                                    max_backtracks: usize)
+                                                    -> Result<(), ModelError> {
+        //! Works like collapse_and_propagate, but when a contradiction is found the most recent
+        //! collapse is undone and the state it chose is banned from that cell before carrying
+        //! on. Only the last `max_depth` collapses can be undone, and after `max_backtracks`
+        //! undos the model gives up with BacktrackLimitReached.
+        let mut rng = self.rng.borrow_mut();
+        self.wave
+            .borrow_mut()
+            .collapse_and_propagate_with_backtracking(&mut *rng, max_depth, max_backtracks)
+    }
 
-        let num_colors = palette.len();
-        let num_states = states.len();
-        let (x, y) = output_dims;
-        let mut model_data = Vec::<UncertainCell>::with_capacity(x * y);
+    pub fn collapse_and_propagate_with_restarts(&self,
+                                                max_attempts: usize,
+                                                max_depth: usize,
+                                                max_backtracks: usize)
+                                                -> Result<usize, ModelError> {
+        //! Runs collapse_and_propagate_with_backtracking, resetting the wave and trying again
+        //! whenever it fails, up to `max_attempts` times. Returns the number of the attempt
+        //! that succeeded, counting from 1, or the error from the final attempt.
+        let mut rng = self.rng.borrow_mut();
+        self.wave
+            .borrow_mut()
+            .collapse_and_propagate_with_restarts(&mut *rng, max_attempts, max_depth, max_backtracks)
+    }
 
-        for _ in 0..(x * y) {
-            model_data.push(UncertainCell::new(num_colors, num_states));
+    pub fn collapse_and_propagate_recording(&self,
+                                            max_depth: usize,
+                                            max_backtracks: usize,
+                                            frame_every: usize,
+                                            file_prefix: &str)
+                                            -> Result<usize, Error> {
+        //! Runs collapse_and_propagate_with_backtracking while saving the image as a numbered
+        //! PNG sequence, `<file_prefix>00000.png` onwards. A frame is saved before the first
+        //! collapse, after every `frame_every` collapses, and once generation stops, even if it
+        //! failed. Returns the number of frames saved, or InvalidFrameInterval if `frame_every`
+        //! is 0.
+        if frame_every == 0 {
+            return Err(Error::InvalidFrameInterval);
         }
-        let model = Array::from_shape_vec((y, x), model_data).unwrap();
-
-        //TODO add wrapping patches
-
-        OverlappingModel {
-            model: model,
-            palette: palette,
-            states: states,
-            state_size: block_size,
-            wrap: WrappingType::NoWrap,
-            color_changes: RefCell::new(HashSet::new()),
-            state_changes: RefCell::new(HashSet::new()),
+        let frame_path = |frame: usize| format!("{}{:05}.png", file_prefix, frame);
+        let mut rng = self.rng.borrow_mut();
+        let mut wave = self.wave.borrow_mut();
+
+        let mut saved = save_image(&self.colors_of(&wave), &frame_path(0));
+        let mut frames = 1;
+        let mut collapses = 0;
+        let result = wave.collapse_and_propagate_observed(&mut *rng,
+                                                         max_depth,
+                                                         max_backtracks,
+                                                         |wave| {
+            collapses += 1;
+            if collapses % frame_every == 0 && saved.is_ok() {
+                saved = save_image(&self.colors_of(wave), &frame_path(frames));
+                frames += 1;
+            }
+        });
+        saved?;
+        if result.is_err() || collapses % frame_every != 0 {
+            save_image(&self.colors_of(&wave), &frame_path(frames))?;
+            frames += 1;
         }
+        result?;
+        Ok(frames)
     }
 
-    pub fn to_image(&self, file_path: &str) {
-        let (y, x) = self.model.dim();
-        let file_path = Path::new(file_path);
-        let file = File::create(file_path).unwrap();
-        let w = &mut BufWriter::new(file);
-        let mut encoder = Encoder::new(w, x as u32, y as u32);
-        encoder.set(ColorType::RGB).set(BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-
-        let mut raw_data = Vec::<u8>::with_capacity(self.model.len() * 3);
-        for rgb in self.model.iter().map(|x| x.to_color(&self.palette)) {
-            raw_data.push(rgb.0);
-            raw_data.push(rgb.1);
-            raw_data.push(rgb.2);
-        }
-        writer.write_image_data(&raw_data).unwrap();
+    pub fn constrain(&self, position: (usize, usize), colors: &[Color]) -> Result<(), Error> {
+        //! Limits the output pixel at `position`, given as (row, column), to the given colors
+        //! and propagates the change, for example to force an entrance at one edge of a map.
+        //! Constraints are kept when the model is reset or restarted. If no pattern fits, the
+        //! model is left as it was and ImpossibleConstraint is returned.
+        let mut wave = self.wave.borrow_mut();
+        let (cell, allowed) = self.pixel_constraint(&wave, position, colors)?;
+        wave.constrain(cell, &allowed).map_err(|_| Error::ImpossibleConstraint(position))
     }
 
-    pub fn collapse_and_propagate(&self) -> Result<(), ModelError> {
-        use overlappingmodel::ModelError::*;
-        loop {
-            let collapse_point = match self.find_lowest_nonzero_entropy_coordinates() {
-                Ok(u) => u,
-                Err(AllStatesDecided) => return Ok(()),
-                Err(NoValidStates(u)) => return Err(NoValidStates(u)),
-                Err(UnexpectedNaN(u)) => return Err(UnexpectedNaN(u)),
-            };
-            self.model[collapse_point].collapse(&self.states);
-            let changes = self.get_downstream_coordinates(collapse_point);
-            {
-                self.color_changes.borrow_mut().extend(changes);
-            }
-
-            'inner: loop {
-                {
-                    let mut color_changes = self.color_changes.borrow_mut();
-                    color_changes.iter().map(|&c| self.update_colors_at_position(c)).count();
-                    color_changes.clear();
-                }
-                {
-                    let mut state_changes = self.state_changes.borrow_mut();
-                    state_changes.iter().map(|&c| self.update_states_at_position(c)).count();
-                    state_changes.clear();
-                }
-                {
-                    if self.model.iter().any(|s| !s.consistent()) {break}
-                }
-                {
-                    let color_changes = self.color_changes.borrow();
-                    let state_changes = self.state_changes.borrow();
-                    if color_changes.is_empty() && state_changes.is_empty() {
-                        break 'inner;
-                    }
-                }
-            }
+    pub fn constrain_with_image(&self,
+                                constraints: &Array2<Color>,
+                                unconstrained: Color)
+                                -> Result<(), Error> {
+        //! Pins every output pixel to the color of the same pixel in `constraints`, which must
+        //! be the size of the output. Pixels of the `unconstrained` color are left free. Either
+        //! every pixel is pinned or, on failure, none are and the model is left as it was.
+        let (rows, columns) = self.output_dim;
+        if constraints.dim() != (rows, columns) {
+            return Err(Error::ConstraintSize(constraints.dim(), (rows, columns)));
         }
+        let pixels: Vec<_> = constraints.indexed_iter()
+            .filter(|&(_, &color)| color != unconstrained)
+            .map(|(position, &color)| (position, color))
+            .collect();
+        self.constrain_pixels(&pixels)
     }
 
-    fn find_lowest_nonzero_entropy_coordinates(&self) -> Result<(usize, usize), ModelError> {
-        let mut output: Option<(usize, usize)> = None;
-        let mut entropy: f64 = f64::MAX;
-        for (index, cell) in self.model.indexed_iter() {
-            match cell.entropy(&self.states) {
-                None => return Err(ModelError::NoValidStates(index)),
-                Some(u) if u > 0. => {
-                    let noise = rand::random::<f64>() * NOISE_LEVEL;
-                    let u = u + noise;
-                    if u < entropy {
-                        entropy = u;
-                        output = Some(index);
-                    } else if u.is_nan() {
-                        return Err(ModelError::UnexpectedNaN(index));
-                    };
-                }
-                Some(_) => continue,
-
-            }
+    pub fn inpaint(&self,
+                   image: &Array2<Color>,
+                   mask: &Array2<bool>,
+                   max_attempts: usize,
+                   max_depth: usize,
+                   max_backtracks: usize)
+                   -> Result<Array2<Color>, Error> {
+        //! Fills the pixels of `image` where `mask` is true with patterns that fit the pixels
+        //! around them, and returns the repaired image. Both must be the size of the output.
+        //! Any earlier constraints are replaced by the kept pixels, which stay pinned after
+        //! this returns. If the kept pixels can't all be pinned the earlier constraints are put
+        //! back, the model is reset and ImpossibleConstraint is returned. Generation is retried
+        //! as in collapse_and_propagate_with_restarts.
+        let dims = self.output_dim;
+        if mask.dim() != dims {
+            return Err(Error::ConstraintSize(mask.dim(), dims));
         }
-        match output {
-            None => Err(ModelError::AllStatesDecided),
-            Some(u) => Ok(u),
+        if image.dim() != dims {
+            return Err(Error::ConstraintSize(image.dim(), dims));
         }
+        let earlier = self.wave.borrow().constraints().to_vec();
+        self.clear_constraints();
+        let pixels: Vec<_> = image.indexed_iter()
+            .zip(mask)
+            .filter(|&(_, &missing)| !missing)
+            .map(|((position, &color), _)| (position, color))
+            .collect();
+        if let Err(e) = self.constrain_pixels(&pixels) {
+            // The earlier constraints held together before, so they also fit the reset wave.
+            let _ = self.wave.borrow_mut().constrain_all(&earlier);
+            return Err(e);
+        }
+        self.collapse_and_propagate_with_restarts(max_attempts, max_depth, max_backtracks)?;
+        Ok(self.pixels())
     }
 
-    fn color_to_index(&self, color: &Color) -> usize {
-        self.palette.binary_search(color).expect("Color not found in palette!")
-    }
-
-    fn get_downstream_coordinates(&self, position: (usize, usize)) -> HashSet<(usize, usize)> {
-        //! This function generates a set of coordinates representing the cells that need to be
-        //! updated due to the cell at position having changes made. The coordinates returned are
-        //! those in an NxN box with 'position' at the top left, or concretely in the 2x2 case:
-        //!  _____________________________
-        //! |     pos     | pos + (0, 1) |
-        //! ------------------------------
-        //! |pos + (1, 0) | pos + (1, 1) |
-        //! -----------------------------
-
-        let s = self.state_size;
-        let mut output = HashSet::with_capacity(s * s);
-        match self.wrap {
-            WrappingType::NoWrap => {
-                for t in 0..s * s {
-                    let offset = (t / s, t % s);
-                    let coordinate = (position.0 + offset.0, position.1 + offset.1);
-                    if self.valid_coord(coordinate) {
-                        output.insert(coordinate);
-                    };
-                }
-            }
-            WrappingType::Torus => unimplemented!(),
+    pub fn outpaint(&self,
+                    image: &Array2<Color>,
+                    offset: (usize, usize),
+                    max_attempts: usize,
+                    max_depth: usize,
+                    max_backtracks: usize)
+                    -> Result<Array2<Color>, Error> {
+        //! Places `image` in the output with its top left pixel at `offset`, given as (row,
+        //! column), and grows new content outwards from it to fill the rest of the output.
+        //! Returns the enlarged image. To extend a map using its own patterns, build the model
+        //! from the map itself with an output larger than it. This works as `inpaint` does with
+        //! everything outside the image missing.
+        let (rows, columns) = self.output_dim;
+        let (height, width) = image.dim();
+        if offset.0 + height > rows || offset.1 + width > columns {
+            return Err(Error::ConstraintSize((offset.0 + height, offset.1 + width),
+                                             (rows, columns)));
         }
-        output
+        let canvas = Array2::from_shape_fn((rows, columns), |(y, x)| {
+            if y >= offset.0 && x >= offset.1 && y < offset.0 + height && x < offset.1 + width {
+                image[(y - offset.0, x - offset.1)]
+            } else {
+                Color(0, 0, 0, 0)
+            }
+        });
+        let mask = Array2::from_shape_fn((rows, columns), |(y, x)| {
+            y < offset.0 || x < offset.1 || y >= offset.0 + height || x >= offset.1 + width
+        });
+        self.inpaint(&canvas, &mask, max_attempts, max_depth, max_backtracks)
     }
 
-    fn get_upstream_coordinates(&self, position: (usize, usize)) -> HashSet<(usize, usize)> {
-        //! This function works similarly to get_downstream_coordinates, but returns coordinates
-        //! up and to the left of the input position and does some additional bounds checking
-        //! to test for potentially negative coordinates before casting back to (usize, usize).
+    pub fn clear_constraints(&self) {
+        //! Removes every constraint and resets the model.
+        let mut wave = self.wave.borrow_mut();
+        wave.clear_constraints();
+        wave.reset();
+    }
 
-        let s = self.state_size;
-        let mut output = HashSet::with_capacity(s * s);
-        let s = s as isize;
-        match self.wrap {
-            WrappingType::NoWrap => {
-                for t in 0..s * s {
-                    let offset = (t / s, t % s);
-                    let coordinate = (position.0 as isize - offset.0,
-                                      position.1 as isize - offset.1);
-                    if self.valid_coord(coordinate) {
-                        let coordinate = (coordinate.0 as usize, coordinate.1 as usize);
-                        output.insert(coordinate);
-                    };
+    pub fn save<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        //! Writes everything needed to carry on generating later with `load`: the palettes,
+        //! the patterns, the size of the output, any constraints and the states still possible
+        //! in every cell.
+        //! The heuristic and listener aren't saved and must be set again after loading.
+        //!
+        //! The state of the random number generator and the heuristic's noise are saved too,
+        //! so this model, which saving leaves untouched, and the loaded copy carry on
+        //! identically.
+        let wave = self.wave.borrow();
+
+        writer.write_all(SAVE_MAGIC)?;
+        write_u64(writer, SAVE_VERSION)?;
+        for &word in &rng_state(&self.rng.borrow()) {
+            write_u64(writer, word as u64)?;
+        }
+        let (y, x) = self.output_dim;
+        write_u64(writer, x as u64)?;
+        write_u64(writer, y as u64)?;
+        writer.write_all(&[match wave.wrap() {
+                              WrappingType::NoWrap => 0,
+                              WrappingType::Torus => 1,
+                          }])?;
+        write_u64(writer, self.state_size as u64)?;
+        write_colors(writer, &self.palette)?;
+        write_colors(writer, &self.output_palette)?;
+        write_u64(writer, self.states.len() as u64)?;
+        for &(ref state, count) in &self.states {
+            write_u64(writer, count as u64)?;
+            // Transformed patterns may not be in standard layout, so can't be used as a slice.
+            write_colors(writer, &state.iter().cloned().collect::<Vec<_>>())?;
+        }
+        write_u64(writer, wave.constraints().len() as u64)?;
+        for &((row, column), ref allowed) in wave.constraints() {
+            write_u64(writer, row as u64)?;
+            write_u64(writer, column as u64)?;
+            writer.write_all(&allowed.to_bytes())?;
+        }
+        let contradiction = wave.cells().into_iter().any(|p| wave.remaining_states(p) == 0);
+        writer.write_all(&[contradiction as u8])?;
+        for position in wave.cells() {
+            writer.write_all(&wave.possible_states(position).to_bytes())?;
+        }
+        match wave.heuristic_noise() {
+            Some(noise) => {
+                writer.write_all(&[1])?;
+                for &value in noise {
+                    write_u64(writer, value.to_bits())?;
                 }
             }
-            WrappingType::Torus => unimplemented!(),
+            None => writer.write_all(&[0])?,
         }
-        output
+        Ok(())
     }
 
-
-    fn update_states_at_position(&self, position: (usize, usize)) {
-        let new_states = self.valid_states_at_position(position);
-        let changed: bool;
-        {
-            changed = self.model[position].possible_states.borrow_mut().intersect(&new_states);
+    pub fn load<R: Read>(reader: &mut R) -> Result<OverlappingModel, Error> {
+        //! Reads a model written by `save`, with every cell as it was when it was saved.
+        //! Returns InvalidSave if the data is truncated or doesn't describe a valid model.
+        let invalid = |message: &str| Error::InvalidSave(message.to_owned());
+        if read_bytes(reader, 4)? != SAVE_MAGIC {
+            return Err(invalid("not a saved model"));
         }
-        if changed {
-            let states_that_need_to_be_looked_at = self.get_downstream_coordinates(position);
-            self.color_changes.borrow_mut().extend(states_that_need_to_be_looked_at);
+        let version = read_u64(reader)?;
+        if version != SAVE_VERSION {
+            return Err(Error::InvalidSave(format!("unknown version {}", version)));
         }
-    }
-
-    fn update_colors_at_position(&self, position: (usize, usize)) {
-        let new_colors = self.valid_colors_at_position(position);
-        let changed: bool;
-        {
-            changed = self.model[position].possible_colors.borrow_mut().intersect(&new_colors);
+        let mut rng_seed = [0; 4];
+        for word in &mut rng_seed {
+            let value = read_u64(reader)?;
+            if value > u32::MAX as u64 {
+                return Err(invalid("random number generator state is corrupt"));
+            }
+            *word = value as u32;
         }
-        if changed {
-            let states_that_need_to_be_looked_at = self.get_upstream_coordinates(position);
-            self.state_changes.borrow_mut().extend(states_that_need_to_be_looked_at)
+        if rng_seed == [0; 4] {
+            return Err(invalid("random number generator state is corrupt"));
+        }
+        let x = read_usize(reader)?;
+        let y = read_usize(reader)?;
+        let wrap = match read_bytes(reader, 1)?[0] {
+            0 => WrappingType::NoWrap,
+            1 => WrappingType::Torus,
+            w => return Err(Error::InvalidSave(format!("unknown wrapping type {}", w))),
+        };
+        let state_size = read_usize(reader)?;
+        let state_len = state_size.checked_mul(state_size)
+            .ok_or_else(|| invalid("pattern size is too large"))?;
+        let palette = read_colors(reader)?;
+        let output_palette = read_colors(reader)?;
+        let state_count = read_usize(reader)?;
+        if state_size == 0 || state_count == 0 {
+            return Err(invalid("there are no patterns"));
+        }
+        let (rows, columns) = wave_dim((x, y), state_size, wrap)
+            .map_err(|_| invalid("the output is too small for its patterns"))?;
+        let mut states = Vec::new();
+        for _ in 0..state_count {
+            let count = read_usize(reader)?;
+            let colors = read_colors(reader)?;
+            if colors.len() != state_len {
+                return Err(invalid("pattern has the wrong size"));
+            }
+            if colors.iter().any(|c| palette.binary_search(c).is_err()) {
+                return Err(invalid("pattern uses a color missing from the palette"));
+            }
+            let state = Array2::from_shape_vec((state_size, state_size), colors).unwrap();
+            states.push((state, count));
         }
 
-    }
-
-    fn valid_states_at_position(&self, position: (usize, usize)) -> BitVec {
-        //! Queries an NxN grid with the top left at function argument "position" for the states
-        //! that their current color possibilities allow, then takes the intersection of all of
-        //! those possibilites.
-
-        let s = self.state_size;
-        let wrap = self.wrap;
-        let mut patch_possibilites = Vec::<BitVec>::with_capacity(s * s);
-        let cell_states = self.model[position].possible_states.borrow();
-
-        for t in 0..s * s {
-            let pixel_coords = (t / s, t % s);
-            let cell_coords = (pixel_coords.0 + position.0, pixel_coords.1 + position.1);
-            match wrap {
-                WrappingType::NoWrap => {
-                    if !self.valid_coord(cell_coords) {
-                        continue;
+        let state_bytes = state_count.div_ceil(8);
+        let mut constraints = Vec::new();
+        for _ in 0..read_u64(reader)? {
+            let row = read_usize(reader)?;
+            let column = read_usize(reader)?;
+            if row >= rows || column >= columns {
+                return Err(invalid("constraint is outside the output"));
+            }
+            let mut allowed = BitVec::from_bytes(&read_bytes(reader, state_bytes as u64)?);
+            allowed.truncate(state_count);
+            constraints.push(((row, column), allowed));
+        }
+        // Every cell is read before the wave is built, so a corrupt size fails here instead of
+        // allocating a huge wave.
+        let cell_bytes = rows.checked_mul(columns)
+            .and_then(|cells| cells.checked_mul(state_bytes))
+            .ok_or_else(|| invalid("output is too large"))?;
+        let contradiction = match read_bytes(reader, 1)?[0] {
+            0 => false,
+            1 => true,
+            c => return Err(Error::InvalidSave(format!("unknown contradiction marker {}", c))),
+        };
+        let cells = read_bytes(reader, cell_bytes as u64)?;
+        let noise = match read_bytes(reader, 1)?[0] {
+            0 => None,
+            1 => {
+                let mut noise = Vec::new();
+                for _ in 0..rows * columns {
+                    let value = f64::from_bits(read_u64(reader)?);
+                    if !value.is_finite() {
+                        return Err(invalid("heuristic noise is not a number"));
                     }
+                    noise.push(value);
                 }
-                WrappingType::Torus => unimplemented!(),
+                Some(Array2::from_shape_vec((rows, columns), noise).unwrap())
             }
+            n => return Err(Error::InvalidSave(format!("unknown noise marker {}", n))),
+        };
 
-
-            let color_states = self.model[cell_coords].possible_colors.borrow();
-            let new_cell_states: BitVec = cell_states.iter()
-                .enumerate()
-                .map(|(i, x)| if x {
-                    let c = self.color_to_index(&self.states[i].0[pixel_coords]);
-                    color_states.get(c).unwrap()
-                } else {
-                    false
-                })
-                .collect();
-
-            patch_possibilites.push(new_cell_states);
+        let model = OverlappingModel::from_states(palette,
+                                                  output_palette,
+                                                  states,
+                                                  state_size,
+                                                  (x, y),
+                                                  wrap)?;
+        model.set_rng(XorShiftRng::from_seed(rng_seed));
+        {
+            let mut wave = model.wave.borrow_mut();
+            for (position, allowed) in constraints {
+                wave.constrain(position, &allowed)
+                    .map_err(|_| invalid("constraints are impossible"))?;
+            }
+            for (position, bytes) in wave.cells().into_iter().zip(cells.chunks(state_bytes)) {
+                let possible = BitVec::from_bytes(bytes);
+                for state in (0..state_count).filter(|&s| !possible[s]) {
+                    wave.ban(position, state);
+                }
+            }
+            // The saved wave was fully propagated, so this only restores the support counts
+            // unless the cells were tampered with.
+            if wave.propagate().is_err() != contradiction {
+                return Err(invalid("cells don't agree with their neighbours"));
+            }
+            if let Some(noise) = noise {
+                wave.restore_heuristic_noise(noise);
+            }
         }
+        Ok(model)
+    }
 
-        mass_intersect(patch_possibilites).unwrap()
+    pub fn reset(&self) {
+        //! Returns every cell to its initial state where all states are possible, reusing the
+        //! patterns that have already been extracted from the seed image.
+        self.wave.borrow_mut().reset();
     }
 
-    fn valid_colors_at_position(&self, position: (usize, usize)) -> BitVec {
-        let wrap = self.wrap;
-        let s: isize = self.state_size.try_into().unwrap();
-        let mut patch_possibilites = Vec::<BitVec>::with_capacity((s * s) as usize);
-        let pos = (position.0 as isize, position.1 as isize);
+    pub fn pixels(&self) -> Array2<Color> {
+        //! Returns the generated image without writing it anywhere. Undecided pixels show the
+        //! average of their possible colors.
+        self.colors_of(&self.wave.borrow())
+    }
 
-        for t in 0..s * s {
-            let pixel_coords = ((t / s) as usize, (t % s) as usize);
-            let offset = (pixel_coords.0 as isize, pixel_coords.1 as isize);
-            let cell_coords = (pos.0 - offset.0, pos.1 - offset.1);
-            match wrap {
-                WrappingType::NoWrap => {
-                    if !self.valid_coord(cell_coords) {
-                        continue;
-                    }
-                }
-                WrappingType::Torus => unimplemented!(),
+    pub fn to_indexed_image(&self, file_path: &str) -> Result<(), Error> {
+        //! Writes the generated image to an indexed PNG file at `file_path`. If the seed image
+        //! was indexed its palette is kept in its original order, otherwise the palette holds
+        //! the seed image's colors in sorted order. Cells that are undecided or contradictory
+        //! are shown in magenta, added at the end of the palette or, if the palette is already
+        //! full, in place of an entry that no pixel uses. Returns PaletteTooLarge if there is
+        //! no room for it.
+        let output_index: Vec<Option<usize>> = self.palette
+            .iter()
+            .map(|c| self.output_palette.iter().position(|p| p == c))
+            .collect();
+
+        let wave = self.wave.borrow();
+        let indices = Array2::from_shape_fn(self.output_dim, |position| {
+            let (cell, offset) = covering_cell(&wave, position);
+            let colors = self.possible_colors(wave.possible_states(cell), offset);
+            match colors.iter().position(|p| p) {
+                Some(c) if colors.iter().filter(|&p| p).count() == 1 => output_index[c],
+                _ => None,
+            }
+        });
+
+        let mut palette = self.output_palette.clone();
+        let mut reserved = 0;
+        if indices.iter().any(Option::is_none) {
+            let mut used = vec![false; palette.len()];
+            for &i in indices.iter().flatten() {
+                used[i] = true;
+            }
+            reserved = match used.iter().position(|&u| !u) {
+                _ if palette.len() < 256 => palette.len(),
+                Some(unused) => unused,
+                None => return Err(Error::PaletteTooLarge(palette.len() + 1)),
+            };
+            if reserved == palette.len() {
+                palette.push(Color(255, 0, 128, 255));
+            } else {
+                palette[reserved] = Color(255, 0, 128, 255);
             }
-            let cell_coords = (cell_coords.0 as usize, cell_coords.1 as usize);
+        }
+        save_indexed_image(&indices.map(|i| i.unwrap_or(reserved)), &palette, file_path)
+    }
 
-            let cell_states = self.model[cell_coords].possible_states.borrow();
+    fn pixel_constraint(&self,
+                        wave: &Wave,
+                        position: (usize, usize),
+                        colors: &[Color])
+                        -> Result<((usize, usize), BitVec), Error> {
+        //! Finds the cell that decides the pixel at `position` and the states of that cell
+        //! that give the pixel one of `colors`.
+        let (rows, columns) = self.output_dim;
+        if position.0 >= rows || position.1 >= columns {
+            return Err(Error::ConstraintOutOfBounds(position, (rows, columns)));
+        }
+        let (cell, offset) = covering_cell(wave, position);
+        let allowed = BitVec::from_fn(self.states.len(), |s| {
+            colors.contains(&self.palette[self.indexed_states[s][offset]])
+        });
+        Ok((cell, allowed))
+    }
 
-            let mut new_color_states: BitVec = BitVec::from_elem(self.palette.len(), false);
+    fn constrain_pixels(&self, pixels: &[((usize, usize), Color)]) -> Result<(), Error> {
+        //! Pins each pixel to its color, all or nothing.
+        let mut wave = self.wave.borrow_mut();
+        let constraints = pixels.iter()
+            .map(|&(position, color)| self.pixel_constraint(&wave, position, &[color]))
+            .collect::<Result<Vec<_>, _>>()?;
+        wave.constrain_all(&constraints).map_err(|(i, _)| Error::ImpossibleConstraint(pixels[i].0))
+    }
 
-            for state_index in cell_states.iter().enumerate().filter(|&(_, s)| s).map(|(i, _)| i) {
-                let v = self.color_to_index(&self.states[state_index].0[pixel_coords]);
-                new_color_states.set(v, true);
-            }
-            patch_possibilites.push(new_color_states);
+    fn colors_of(&self, wave: &Wave) -> Array2<Color> {
+        Array2::from_shape_fn(self.output_dim, |position| {
+            let (cell, offset) = covering_cell(wave, position);
+            self.to_color(wave.possible_states(cell), offset)
+        })
+    }
 
+    fn possible_colors(&self, possible_states: &BitVec, offset: (usize, usize)) -> BitVec {
+        //! Returns which entries of the palette are still possible at `offset` within the
+        //! patterns, given the possible states.
+        let mut colors = BitVec::from_elem(self.palette.len(), false);
+        for (state, _) in possible_states.iter().enumerate().filter(|&(_, p)| p) {
+            colors.set(self.indexed_states[state][offset], true);
         }
-
-        mass_intersect(patch_possibilites).unwrap()
+        colors
     }
 
-    fn valid_coord<T: TryInto<usize>>(&self, coord: (T, T)) -> bool {
-        let y: usize = match coord.0.try_into() {
-            Ok(u) => u,
-            Err(_) => return false,
-        };
-        let x: usize = match coord.1.try_into() {
-            Ok(u) => u,
-            Err(_) => return false,
-        };
-        let (safe_y, safe_x) = self.model.dim();
+    fn to_color(&self, possible_states: &BitVec, offset: (usize, usize)) -> Color {
+        //! Returns the average color of all remaining possible colors, or magenta if there are
+        //! none.
+        let colors = self.possible_colors(possible_states, offset);
+        let possible_colors = self.palette
+            .iter()
+            .zip(colors.iter())
+            .filter(|&(_, p)| p)
+            .map(|(&c, _)| c);
+        Color::average(possible_colors).unwrap_or(Color(255, 0, 128, 255))
+    }
 
-        (y < safe_y) && (x < safe_x)
+    fn build_propagator(states: &[Array2<usize>]) -> Vec<Vec<Vec<usize>>> {
+        //! For each direction and state, lists the states whose overlapping pixels agree when
+        //! placed one cell over in that direction. Agreeing with all four neighbours is enough
+        //! for agreement with every overlapping pattern, since each shared pixel can be traced
+        //! through a chain of neighbours.
+        DIRECTIONS.iter()
+            .map(|&offset| {
+                states.iter()
+                    .map(|s1| {
+                        states.iter()
+                            .enumerate()
+                            .filter(|&(_, s2)| OverlappingModel::agrees(s1, s2, offset))
+                            .map(|(i, _)| i)
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect()
     }
 
-    fn wrap_coord<T: TryInto<usize>>(&self, coord: (T, T)) -> Result<(usize, usize), ()> {
-        unimplemented!()
+    fn agrees(s1: &Array2<usize>, s2: &Array2<usize>, offset: (isize, isize)) -> bool {
+        //! Returns true if s2, placed at offset from s1, has the same colors wherever they overlap.
+        let n = s1.dim().0 as isize;
+        let (dy, dx) = offset;
+        for ((y, x), &c) in s2.indexed_iter() {
+            let (sy, sx) = (y as isize + dy, x as isize + dx);
+            if sy >= 0 && sy < n && sx >= 0 && sx < n && s1[(sy as usize, sx as usize)] != c {
+                return false;
+            }
+        }
+        true
     }
 
     fn build_color_palette(image_data: &Array2<Color>) -> Vec<Color> {
@@ -429,52 +683,162 @@ impl OverlappingModel {
         palette
     }
 
+    fn image_blocks<T: Copy>(image_data: &Array2<T>,
+                             block_size: usize,
+                             wrap: WrappingType)
+                             -> Vec<Array2<T>> {
+        //! Returns every NxN block of the image. With WrappingType::Torus the image is treated
+        //! as periodic, so there is one block for every pixel including those whose blocks cross
+        //! the right and bottom edges.
+        match wrap {
+            WrappingType::NoWrap => {
+                image_data.windows((block_size, block_size)).into_iter().map(|b| b.to_owned()).collect()
+            }
+            WrappingType::Torus => {
+                let (y, x) = image_data.dim();
+                image_data.indexed_iter()
+                    .map(|((i, j), _)| {
+                        Array2::from_shape_fn((block_size, block_size),
+                                              |(a, b)| image_data[((i + a) % y, (j + b) % x)])
+                    })
+                    .collect()
+            }
+        }
+    }
+
     fn build_block_frequency_map<T: Copy + Eq + Hash>(image_data: &Array2<T>,
-                                                      block_size: usize)
+                                                      block_size: usize,
+                                                      wrap: WrappingType)
                                                       -> Vec<(Array2<T>, usize)> {
-        let mut block_counts = HashMap::new();
+        //! Counts each distinct block of the image. Blocks are listed in the order they are
+        //! first found, so state indices don't depend on HashMap iteration order.
+        let mut block_counts = Vec::<(Array2<T>, usize)>::new();
+        let mut block_indices = HashMap::<Array2<T>, usize>::new();
 
-        for block in image_data.windows((block_size, block_size)) {
-            let block = block.to_owned();
-            let count = block_counts.entry(block).or_insert(0);
-            *count += 1;
+        for block in OverlappingModel::image_blocks(image_data, block_size, wrap) {
+            OverlappingModel::count_block(&mut block_counts, &mut block_indices, block);
         }
 
-        block_counts.into_iter().collect()
+        block_counts
     }
 
     fn build_augmented_block_frequency_map<T: Copy + Eq + Hash>(image_data: &Array2<T>,
-                                                                block_size: usize)
+                                                                block_size: usize,
+                                                                wrap: WrappingType,
+                                                                symmetry: &Symmetry)
                                                                 -> Vec<(Array2<T>, usize)> {
-        let mut block_counts = HashMap::<Array2<_>, usize>::new();
+        let mut block_counts = Vec::<(Array2<T>, usize)>::new();
+        let mut block_indices = HashMap::<Array2<T>, usize>::new();
 
-        for block in image_data.windows((block_size, block_size)) {
-            let blocks = generate_rotations_and_reflections(&block.to_owned());
+        for block in OverlappingModel::image_blocks(image_data, block_size, wrap) {
+            let blocks = symmetry.apply(&block);
             for b in blocks {
-                let count = block_counts.entry(b).or_insert(0);
-                *count += 1;
+                OverlappingModel::count_block(&mut block_counts, &mut block_indices, b);
             }
         }
 
-        block_counts.into_iter().collect()
+        block_counts
     }
+
+    fn count_block<T: Copy + Eq + Hash>(block_counts: &mut Vec<(Array2<T>, usize)>,
+                                        block_indices: &mut HashMap<Array2<T>, usize>,
+                                        block: Array2<T>) {
+        if let Some(&index) = block_indices.get(&block) {
+            block_counts[index].1 += 1;
+            return;
+        }
+        block_indices.insert(block.clone(), block_counts.len());
+        block_counts.push((block, 1));
+    }
+}
+
+fn wave_dim(output_dims: (usize, usize),
+            block_size: usize,
+            output_wrap: WrappingType)
+            -> Result<(usize, usize), Error> {
+    //! Returns the rows and columns of cells needed for an output of `output_dims` pixels. A
+    //! wrapping output has a cell for every pixel. Otherwise there are only cells where a
+    //! whole pattern fits inside the output, and the pixels along the bottom and right edges
+    //! come from the patterns of the cells above and to the left of them.
+    let (x, y) = output_dims;
+    match output_wrap {
+        WrappingType::Torus if x > 0 && y > 0 => Ok((y, x)),
+        WrappingType::NoWrap if x >= block_size && y >= block_size => {
+            Ok((y + 1 - block_size, x + 1 - block_size))
+        }
+        _ => Err(Error::InvalidOutputSize(block_size, (y, x))),
+    }
+}
+
+fn covering_cell(wave: &Wave, pixel: (usize, usize)) -> ((usize, usize), (usize, usize)) {
+    //! Returns the cell whose pattern decides `pixel`, along with the pixel's position in the
+    //! pattern.
+    let (rows, columns) = wave.dim();
+    let cell = (pixel.0.min(rows - 1), pixel.1.min(columns - 1));
+    (cell, (pixel.0 - cell.0, pixel.1 - cell.1))
+}
+
+const SAVE_MAGIC: &[u8; 4] = b"WFCM";
+const SAVE_VERSION: u64 = 3;
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> Result<(), Error> {
+    let bytes: Vec<u8> = (0..8).map(|i| (value >> (8 * i)) as u8).collect();
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+    //! Reads exactly `len` bytes. The buffer only grows as data arrives, so a corrupt length
+    //! can't allocate more memory than there is data.
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(Error::InvalidSave("data is truncated".to_owned()));
+    }
+    Ok(bytes)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let bytes = read_bytes(reader, 8)?;
+    Ok(bytes.iter().rev().fold(0, |value, &b| (value << 8) | b as u64))
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> Result<usize, Error> {
+    let value = read_u64(reader)?;
+    if value > usize::MAX as u64 {
+        return Err(Error::InvalidSave(format!("{} is too large", value)));
+    }
+    Ok(value as usize)
+}
+
+fn write_colors<W: Write>(writer: &mut W, colors: &[Color]) -> Result<(), Error> {
+    write_u64(writer, colors.len() as u64)?;
+    let bytes: Vec<u8> = colors.iter().flat_map(|c| vec![c.0, c.1, c.2, c.3]).collect();
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn read_colors<R: Read>(reader: &mut R) -> Result<Vec<Color>, Error> {
+    let len = read_u64(reader)?;
+    let bytes = read_bytes(reader, len.saturating_mul(4))?;
+    Ok(bytes.chunks(4).map(|c| Color(c[0], c[1], c[2], c[3])).collect())
 }
 
 #[test]
 fn color_palette_test() {
     let array = Array2::from_shape_vec((3, 3),
-                                       vec![Color(0, 0, 0),
-                                            Color(1, 1, 1),
-                                            Color(1, 1, 1),
-                                            Color(0, 0, 0),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 2)])
+                                       vec![Color(0, 0, 0, 255),
+                                            Color(1, 1, 1, 255),
+                                            Color(1, 1, 1, 255),
+                                            Color(0, 0, 0, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 2, 255)])
         .unwrap();
 
-    let p = vec![Color(0, 0, 0), Color(0, 0, 1), Color(0, 0, 2), Color(1, 1, 1)];
+    let p = vec![Color(0, 0, 0, 255), Color(0, 0, 1, 255), Color(0, 0, 2, 255), Color(1, 1, 1, 255)];
     let p_test = OverlappingModel::build_color_palette(&array);
     assert_eq!(p, p_test);
 }
@@ -482,24 +846,614 @@ fn color_palette_test() {
 #[test]
 fn build_block_frequency_map_test_1() {
     let array = Array2::from_shape_vec((3, 3),
-                                       vec![Color(0, 0, 0),
-                                            Color(1, 1, 1),
-                                            Color(1, 1, 1),
-                                            Color(0, 0, 0),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 1),
-                                            Color(0, 0, 2)])
+                                       vec![Color(0, 0, 0, 255),
+                                            Color(1, 1, 1, 255),
+                                            Color(1, 1, 1, 255),
+                                            Color(0, 0, 0, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 1, 255),
+                                            Color(0, 0, 2, 255)])
         .unwrap();
-    let p_test = OverlappingModel::build_block_frequency_map(&array, 2);
+    let p_test = OverlappingModel::build_block_frequency_map(&array, 2, WrappingType::NoWrap);
     assert_eq!(p_test.len(), 4);
 }
 
 #[test]
 fn build_block_frequency_map_test_2() {
     let array: Array2<usize> = Array2::eye(10);
-    let p_test = OverlappingModel::build_block_frequency_map(&array, 2);
+    let p_test = OverlappingModel::build_block_frequency_map(&array, 2, WrappingType::NoWrap);
     let p_count: usize = p_test.iter().map(|&(_, u)| u).sum();
     assert_eq!(p_count, 81);
 }
+
+#[cfg(test)]
+fn checkerboard_seed_image(size: usize) -> SeedImage {
+    use png::{OutputInfo, ColorType, BitDepth};
+    let data = (0..size * size)
+        .map(|i| {
+            if (i / size + i % size).is_multiple_of(2) {
+                Color(0, 0, 0, 255)
+            } else {
+                Color(255, 255, 255, 255)
+            }
+        })
+        .collect();
+    SeedImage {
+        image_data: Array2::from_shape_vec((size, size), data).unwrap(),
+        image_info: OutputInfo {
+            width: size as u32,
+            height: size as u32,
+            color_type: ColorType::RGB,
+            bit_depth: BitDepth::Eight,
+            line_size: size * 3,
+        },
+        palette: None,
+    }
+}
+
+#[cfg(test)]
+fn knot_seed_image() -> SeedImage {
+    SeedImage::from_file("assets/Knot.png").unwrap()
+}
+
+#[cfg(test)]
+fn only_learned_patterns(model: &OverlappingModel, image: &Array2<Color>) -> bool {
+    let n = model.state_size;
+    OverlappingModel::image_blocks(image, n, WrappingType::NoWrap)
+        .iter()
+        .all(|block| model.states.iter().any(|(state, _)| state == block))
+}
+
+#[cfg(test)]
+fn test_model(seed_image: SeedImage,
+              block_size: usize,
+              output_dims: (usize, usize),
+              output_wrap: WrappingType)
+              -> OverlappingModel {
+    //! Learns every orientation of the patterns in `seed_image`, treating it as periodic.
+    OverlappingModel::from_seed_image(seed_image,
+                                      output_dims,
+                                      block_size,
+                                      output_wrap,
+                                      WrappingType::Torus,
+                                      &Symmetry::all())
+        .unwrap()
+}
+
+#[test]
+fn agrees_test() {
+    let s1 = Array2::from_shape_vec((2, 2), vec![0, 1, 2, 3]).unwrap();
+    let s2 = Array2::from_shape_vec((2, 2), vec![1, 4, 3, 5]).unwrap();
+    assert!(OverlappingModel::agrees(&s1, &s2, (0, 1)));
+    assert!(!OverlappingModel::agrees(&s1, &s2, (1, 0)));
+    assert!(OverlappingModel::agrees(&s2, &s1, (0, -1)));
+}
+
+#[test]
+fn torus_output_tiles_test() {
+    let model = test_model(checkerboard_seed_image(4), 2, (6, 6), WrappingType::Torus);
+    assert!(model.collapse_and_propagate().is_ok());
+    let colors = model.pixels();
+    let (y, x) = colors.dim();
+    for ((i, j), &color) in colors.indexed_iter() {
+        assert!(color != colors[((i + 1) % y, j)]);
+        assert!(color != colors[(i, (j + 1) % x)]);
+    }
+}
+
+#[test]
+fn build_block_frequency_map_periodic_test() {
+    let array: Array2<usize> = Array2::eye(10);
+    let p_test = OverlappingModel::build_block_frequency_map(&array, 2, WrappingType::Torus);
+    let p_count: usize = p_test.iter().map(|&(_, u)| u).sum();
+    assert_eq!(p_count, 100);
+    // The wrap-around block at the bottom right corner joins the last 1 back to the first.
+    let corner = Array2::from_shape_vec((2, 2), vec![1, 0, 0, 1]).unwrap();
+    let diagonal = p_test.iter().find(|p| p.0 == corner).unwrap();
+    assert_eq!(diagonal.1, 10);
+}
+
+#[test]
+fn backtracking_test() {
+    use std::rc::Rc;
+
+    let model = test_model(checkerboard_seed_image(4), 2, (6, 6), WrappingType::Torus);
+    assert!(model.collapse_and_propagate_with_backtracking(10, 10).is_ok());
+
+    // A checkerboard cannot wrap around an odd sized torus, so every choice gets undone.
+    let model = test_model(checkerboard_seed_image(4), 2, (5, 5), WrappingType::Torus);
+    match model.collapse_and_propagate_with_backtracking(10, 10) {
+        Err(ModelError::NoValidStates(_)) => {}
+        u => panic!("Expected NoValidStates, found {:?}", u),
+    }
+    let model = test_model(checkerboard_seed_image(4), 2, (5, 5), WrappingType::Torus);
+    match model.collapse_and_propagate_with_backtracking(10, 0) {
+        Err(ModelError::BacktrackLimitReached) => {}
+        u => panic!("Expected BacktrackLimitReached, found {:?}", u),
+    }
+
+    // This seed fails at the 52nd collapse without backtracking, but carries on with it.
+    #[derive(Default)]
+    struct Restored(usize);
+    impl Listener for Restored {
+        fn state_restored(&mut self, _position: (usize, usize), _state: usize) {
+            self.0 += 1;
+        }
+    }
+    let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    model.reseed(16);
+    assert!(model.collapse_and_propagate().is_err());
+    let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    let restored = Rc::new(RefCell::new(Restored::default()));
+    model.set_listener(restored.clone());
+    model.reseed(16);
+    model.collapse_and_propagate_with_backtracking(20, 200).unwrap();
+    assert!(restored.borrow().0 > 0);
+    assert!(only_learned_patterns(&model, &model.pixels()));
+}
+
+#[test]
+fn restart_test() {
+    let model = test_model(checkerboard_seed_image(4), 2, (6, 6), WrappingType::Torus);
+    assert_eq!(model.collapse_and_propagate_with_restarts(3, 0, 0).unwrap(), 1);
+
+    let model = test_model(checkerboard_seed_image(4), 2, (5, 5), WrappingType::Torus);
+    assert!(model.collapse_and_propagate_with_restarts(3, 0, 0).is_err());
+    model.reset();
+    let wave = model.wave.borrow();
+    let (y, x) = wave.dim();
+    for position in (0..y * x).map(|i| (i / x, i % x)) {
+        assert!(wave.possible_states(position).all());
+    }
+}
+
+#[test]
+fn build_block_frequency_map_order_test() {
+    let array: Array2<usize> = Array2::eye(10);
+    let p_test = OverlappingModel::build_block_frequency_map(&array, 2, WrappingType::NoWrap);
+    let blocks: Vec<_> = p_test.iter().map(|p| p.0.iter().cloned().collect::<Vec<_>>()).collect();
+    assert_eq!(blocks, vec![vec![1, 0, 0, 1], vec![0, 0, 1, 0], vec![0, 0, 0, 0], vec![0, 1, 0, 0]]);
+}
+
+#[test]
+fn seeded_generation_test() {
+    let generate = |seed| {
+        let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+        model.reseed(seed);
+        model.collapse_and_propagate_with_backtracking(20, 200).unwrap();
+        model.pixels()
+    };
+    assert_eq!(generate(3), generate(3));
+    assert!(generate(3) != generate(4));
+}
+
+#[test]
+fn heuristic_test() {
+    use heuristic::{Scanline, Spiral, Random, MinimumRemainingValues};
+    let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    model.reseed(1);
+    model.set_heuristic(Scanline::new());
+    assert!(model.collapse_and_propagate_with_restarts(5, 20, 200).is_ok());
+    model.reset();
+    model.set_heuristic(Spiral::new());
+    assert!(model.collapse_and_propagate_with_restarts(5, 20, 200).is_ok());
+    model.reset();
+    model.set_heuristic(Random::new());
+    assert!(model.collapse_and_propagate_with_restarts(5, 20, 200).is_ok());
+    model.reset();
+    model.set_heuristic(MinimumRemainingValues::new());
+    assert!(model.collapse_and_propagate_with_restarts(5, 20, 200).is_ok());
+}
+
+#[test]
+fn build_augmented_block_frequency_map_symmetry_test() {
+    // Sky above ground: mirroring keeps the sky on top, rotating doesn't.
+    let array = Array2::from_shape_vec((2, 2), vec![0, 0, 1, 1]).unwrap();
+    let mirrored = OverlappingModel::build_augmented_block_frequency_map(&array,
+                                                                         2,
+                                                                         WrappingType::NoWrap,
+                                                                         &Symmetry::from_count(2)
+                                                                             .unwrap());
+    assert_eq!(mirrored, vec![(array.clone(), 2)]);
+    let all = OverlappingModel::build_augmented_block_frequency_map(&array,
+                                                                    2,
+                                                                    WrappingType::NoWrap,
+                                                                    &Symmetry::all());
+    assert_eq!(all.len(), 4);
+    assert_eq!(all.iter().map(|p| p.1).sum::<usize>(), 8);
+}
+
+#[test]
+fn invalid_pattern_size_test() {
+    for &size in &[0, 5] {
+        match OverlappingModel::from_seed_image(checkerboard_seed_image(4),
+                                                (8, 8),
+                                                size,
+                                                WrappingType::NoWrap,
+                                                WrappingType::Torus,
+                                                &Symmetry::none()) {
+            Err(Error::InvalidPatternSize(s, (4, 4))) => assert_eq!(s, size),
+            _ => panic!("Expected InvalidPatternSize for a pattern size of {}", size),
+        }
+    }
+
+    // Without wrapping the output needs room for a whole pattern.
+    match OverlappingModel::from_seed_image(checkerboard_seed_image(4),
+                                            (8, 2),
+                                            3,
+                                            WrappingType::NoWrap,
+                                            WrappingType::Torus,
+                                            &Symmetry::none()) {
+        Err(Error::InvalidOutputSize(3, (2, 8))) => {}
+        _ => panic!("Expected InvalidOutputSize"),
+    }
+    assert!(test_model(checkerboard_seed_image(4), 3, (8, 2), WrappingType::Torus)
+        .collapse_and_propagate()
+        .is_ok());
+}
+
+#[test]
+fn transparent_sample_test() {
+    let mut seed_image = checkerboard_seed_image(4);
+    seed_image.image_data.mapv_inplace(|c| if c.0 == 0 { Color(0, 0, 0, 0) } else { c });
+    let model = test_model(seed_image, 2, (6, 6), WrappingType::NoWrap);
+    model.reseed(5);
+    model.collapse_and_propagate().unwrap();
+    let pixels = model.pixels();
+    assert!(pixels.iter().any(|c| c.3 == 0));
+    assert!(pixels.iter().all(|&c| c == Color(0, 0, 0, 0) || c == Color(255, 255, 255, 255)));
+}
+
+#[test]
+fn indexed_output_test() {
+    // The palette lists the sample's colors out of order, along with one it doesn't use.
+    let mut seed_image = knot_seed_image();
+    let mut palette = OverlappingModel::build_color_palette(&seed_image.image_data);
+    palette.reverse();
+    palette.insert(1, Color(7, 7, 7, 255));
+    seed_image.palette = Some(palette.clone());
+    let model = test_model(seed_image, 3, (12, 10), WrappingType::Torus);
+    let path = ::std::env::temp_dir().join("rust-wfc-indexed-output.png");
+    let path = path.to_string_lossy();
+
+    // Before generating every cell is undecided, so all use the reserved entry.
+    model.to_indexed_image(&path).unwrap();
+    let loaded = SeedImage::from_file(&path).unwrap();
+    let mut expected_palette = palette.clone();
+    expected_palette.push(Color(255, 0, 128, 255));
+    assert_eq!(loaded.palette, Some(expected_palette));
+    assert!(loaded.image_data.iter().all(|&c| c == Color(255, 0, 128, 255)));
+
+    model.reseed(2);
+    model.collapse_and_propagate_with_backtracking(20, 200).unwrap();
+    model.to_indexed_image(&path).unwrap();
+    let loaded = SeedImage::from_file(&path).unwrap();
+    assert_eq!(loaded.palette, Some(palette));
+    assert_eq!(loaded.image_data, model.pixels());
+}
+
+#[test]
+fn recording_test() {
+    let model = test_model(checkerboard_seed_image(4), 2, (5, 5), WrappingType::NoWrap);
+    let directory = ::std::env::temp_dir().join("rust-wfc-recording-test");
+    let _ = ::std::fs::remove_dir_all(&directory);
+    ::std::fs::create_dir_all(&directory).unwrap();
+    let prefix = directory.join("frame-").to_string_lossy().into_owned();
+
+    // A checkerboard is decided by its first collapse, so there is a frame before and after.
+    let frames = model.collapse_and_propagate_recording(0, 0, 1, &prefix).unwrap();
+    assert_eq!(frames, 2);
+    let first = SeedImage::from_file(&format!("{}00000.png", prefix)).unwrap();
+    assert!(first.image_data.iter().all(|&c| c == Color(127, 127, 127, 255)));
+    let last = SeedImage::from_file(&format!("{}00001.png", prefix)).unwrap();
+    assert_eq!(last.image_data, model.pixels());
+    assert!(SeedImage::from_file(&format!("{}00002.png", prefix)).is_err());
+
+    match model.collapse_and_propagate_recording(0, 0, 0, &prefix) {
+        Err(Error::InvalidFrameInterval) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Stepping a twin model through the same collapses shows what each frame should hold.
+    let frame = |i: usize| SeedImage::from_file(&format!("{}{:05}.png", prefix, i));
+    let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    let twin = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    model.reseed(6);
+    twin.reseed(6);
+    let mut collapses = 0;
+    let mut every_third = Vec::new();
+    while let Step::Collapsed(..) = twin.step().unwrap() {
+        collapses += 1;
+        if collapses % 3 == 0 {
+            every_third.push(twin.pixels());
+        }
+    }
+    assert!(collapses % 3 != 0);
+    let frames = model.collapse_and_propagate_recording(0, 0, 3, &prefix).unwrap();
+    // The last frame comes after the final, incomplete batch of collapses.
+    assert_eq!(frames, every_third.len() + 2);
+    for (i, pixels) in every_third.iter().enumerate() {
+        assert_eq!(&frame(i + 1).unwrap().image_data, pixels);
+    }
+    assert_eq!(frame(frames - 1).unwrap().image_data, model.pixels());
+    assert_eq!(model.pixels(), twin.pixels());
+
+    // This seed fails without backtracking after 51 collapses. The last batch of three is
+    // saved as usual, and the failure still gets a frame of its own after it.
+    let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    let twin = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    model.reseed(16);
+    twin.reseed(16);
+    for _ in 0..51 {
+        assert!(matches!(twin.step().unwrap(), Step::Collapsed(..)));
+    }
+    let _ = ::std::fs::remove_dir_all(&directory);
+    ::std::fs::create_dir_all(&directory).unwrap();
+    match model.collapse_and_propagate_recording(0, 0, 3, &prefix) {
+        Err(Error::Model(ModelError::NoValidStates(_))) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(frame(17).unwrap().image_data, twin.pixels());
+    assert_eq!(frame(18).unwrap().image_data, model.pixels());
+    assert!(frame(18).unwrap().image_data != twin.pixels());
+    assert!(frame(19).is_err());
+}
+
+#[test]
+fn step_test() {
+    let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+    model.reseed(6);
+    let mut collapses = 0;
+    loop {
+        let before = model.pixels();
+        match model.step().unwrap() {
+            Step::Collapsed(..) => collapses += 1,
+            Step::Finished => break,
+            s => panic!("Expected a collapse, got {:?}", s),
+        }
+        assert!(model.pixels() != before);
+    }
+    assert!(collapses > 1);
+    assert!(only_learned_patterns(&model, &model.pixels()));
+    assert_eq!(model.step().unwrap(), Step::Finished);
+}
+
+#[test]
+fn save_and_load_test() {
+    let seed_image = knot_seed_image();
+    let corner = seed_image.image_data[(0, 0)];
+    let model = test_model(seed_image, 3, (16, 16), WrappingType::Torus);
+    model.reseed(8);
+    model.constrain((0, 0), &[corner]).unwrap();
+    for _ in 0..5 {
+        assert!(matches!(model.step().unwrap(), Step::Collapsed(..)));
+    }
+
+    let mut saved = Vec::new();
+    model.save(&mut saved).unwrap();
+    let loaded = OverlappingModel::load(&mut &saved[..]).unwrap();
+    assert_eq!(loaded.pixels(), model.pixels());
+    assert_eq!(loaded.states, model.states);
+    assert_eq!(loaded.wave.borrow().constraints(), model.wave.borrow().constraints());
+
+    model.collapse_and_propagate_with_backtracking(20, 200).unwrap();
+    loaded.collapse_and_propagate_with_backtracking(20, 200).unwrap();
+    assert_eq!(loaded.pixels(), model.pixels());
+
+    // Saving doesn't disturb the model, even with a generator set by the caller.
+    let generate = |save: bool| {
+        let model = test_model(knot_seed_image(), 3, (16, 16), WrappingType::Torus);
+        model.set_rng(seeded_rng(8));
+        for _ in 0..5 {
+            model.step().unwrap();
+        }
+        if save {
+            model.save(&mut Vec::new()).unwrap();
+        }
+        model.collapse_and_propagate_with_backtracking(20, 200).unwrap();
+        model.pixels()
+    };
+    assert_eq!(generate(true), generate(false));
+
+    let is_invalid = |data: &[u8]| {
+        matches!(OverlappingModel::load(&mut &data[..]), Err(Error::InvalidSave(_)))
+    };
+    assert!(is_invalid(&saved[..10]));
+    assert!(is_invalid(&saved[..saved.len() - 1]));
+    assert!(is_invalid(b"not a model"));
+    // After the magic number come the version, four words of generator state, the width,
+    // the height, a wrapping byte, the pattern size and then the palette's length.
+    let mut corrupt = saved.clone();
+    corrupt[69..77].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x40]);
+    assert!(is_invalid(&corrupt));
+    let mut corrupt = saved.clone();
+    corrupt[52..60].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x40]);
+    assert!(is_invalid(&corrupt));
+    let mut corrupt = saved.clone();
+    corrupt[12..44].copy_from_slice(&[0; 32]);
+    assert!(is_invalid(&corrupt));
+
+    // The last bytes are the noise marker and the cells, one byte each. A checkerboard can't
+    // have the same pattern in two neighbouring cells.
+    let model = test_model(checkerboard_seed_image(4), 2, (6, 5), WrappingType::NoWrap);
+    let mut saved = Vec::new();
+    model.save(&mut saved).unwrap();
+    assert!(OverlappingModel::load(&mut &saved[..]).is_ok());
+    let cells = saved.len() - 21;
+    saved[cells..cells + 2].copy_from_slice(&[0b1000_0000, 0b1000_0000]);
+    assert!(is_invalid(&saved));
+
+    // A model saved after it failed loads, and is still in contradiction.
+    let model = test_model(checkerboard_seed_image(4), 2, (5, 5), WrappingType::Torus);
+    model.reseed(1);
+    assert!(model.collapse_and_propagate().is_err());
+    let mut saved = Vec::new();
+    model.save(&mut saved).unwrap();
+    let loaded = OverlappingModel::load(&mut &saved[..]).unwrap();
+    assert!(matches!(loaded.step(), Ok(Step::Contradiction(_))));
+}
+
+#[test]
+fn constrain_test() {
+    let black = Color(0, 0, 0, 255);
+    let white = Color(255, 255, 255, 255);
+    let model = test_model(checkerboard_seed_image(4), 2, (6, 5), WrappingType::NoWrap);
+    model.constrain((2, 3), &[white]).unwrap();
+    assert_eq!(model.pixels()[(0, 0)], black);
+    assert_eq!(model.pixels()[(2, 3)], white);
+
+    // A checkerboard can't have matching colors next to each other.
+    match model.constrain((2, 4), &[white]) {
+        Err(Error::ImpossibleConstraint((2, 4))) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(model.constrain((0, 0), &[Color(1, 2, 3, 255)]).is_err());
+    assert_eq!(model.pixels()[(2, 4)], black);
+
+    model.reseed(3);
+    model.collapse_and_propagate_with_restarts(2, 0, 0).unwrap();
+    assert_eq!(model.pixels()[(2, 3)], white);
+    model.reset();
+    assert_eq!(model.pixels()[(0, 0)], black);
+    model.clear_constraints();
+    assert!(model.pixels()[(0, 0)] != black);
+
+    let key = Color(255, 0, 255, 255);
+    let mut image = Array2::from_elem((5, 6), key);
+    image[(4, 5)] = black;
+    model.constrain_with_image(&image, key).unwrap();
+    assert_eq!(model.pixels()[(0, 0)], white);
+    assert!(model.constrain_with_image(&Array2::from_elem((6, 5), key), key).is_err());
+    match model.constrain((5, 0), &[white]) {
+        Err(Error::ConstraintOutOfBounds((5, 0), (5, 6))) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // A failed image leaves none of its pixels pinned, even those before the failing one.
+    model.clear_constraints();
+    image[(0, 0)] = black;
+    image[(0, 1)] = black;
+    match model.constrain_with_image(&image, key) {
+        Err(Error::ImpossibleConstraint((0, 1))) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(model.wave.borrow().constraints().is_empty());
+    assert!(model.pixels()[(0, 0)] != black);
+
+    // So does a failed inpaint, which also puts the earlier constraints back.
+    model.constrain((2, 3), &[white]).unwrap();
+    let mask = Array2::from_elem((5, 6), false);
+    let image = Array2::from_elem((5, 6), black);
+    match model.inpaint(&image, &mask, 2, 0, 0) {
+        Err(Error::ImpossibleConstraint(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(model.wave.borrow().constraints().len(), 1);
+    assert_eq!(model.pixels()[(0, 0)], black);
+}
+
+#[test]
+fn inpaint_test() {
+    let mut damaged = checkerboard_seed_image(6);
+    let original = damaged.image_data.clone();
+    let mut mask = Array2::from_elem((6, 6), false);
+    for &position in &[(2, 2), (2, 3), (3, 3), (5, 0)] {
+        damaged.image_data[position] = Color(200, 0, 0, 255);
+        mask[position] = true;
+    }
+
+    let model = OverlappingModel::for_inpainting(&damaged, &mask, 2, &Symmetry::all()).unwrap();
+    model.reseed(4);
+    assert_eq!(model.inpaint(&damaged.image_data, &mask, 2, 10, 100).unwrap(), original);
+
+    // Patterns can come from a different sample as well.
+    let model = test_model(checkerboard_seed_image(4), 3, (6, 6), WrappingType::NoWrap);
+    model.reseed(4);
+    assert_eq!(model.inpaint(&damaged.image_data, &mask, 2, 10, 100).unwrap(), original);
+
+    assert!(model.inpaint(&damaged.image_data, &Array2::from_elem((6, 5), false), 1, 0, 0)
+        .is_err());
+    assert!(OverlappingModel::for_inpainting(&damaged,
+                                             &Array2::from_elem((6, 6), true),
+                                             2,
+                                             &Symmetry::all())
+        .is_err());
+
+    // A hole in the knot is filled with patterns from the rest of it, including along the
+    // bottom and right edges where patterns would overhang the image.
+    let mut damaged = knot_seed_image();
+    let mut mask = Array2::from_elem(damaged.image_data.dim(), false);
+    for position in (0..16).map(|i| (3 + i / 4, 3 + i % 4)).chain(vec![(16, 16)]) {
+        damaged.image_data[position] = Color(200, 0, 0, 255);
+        mask[position] = true;
+    }
+    let model = OverlappingModel::for_inpainting(&damaged, &mask, 3, &Symmetry::all()).unwrap();
+    model.reseed(4);
+    let result = model.inpaint(&damaged.image_data, &mask, 5, 20, 200).unwrap();
+    for ((position, &color), &missing) in damaged.image_data.indexed_iter().zip(&mask) {
+        assert!(missing || result[position] == color);
+    }
+    assert!(only_learned_patterns(&model, &result));
+}
+
+#[test]
+fn outpaint_test() {
+    let image = checkerboard_seed_image(4).image_data;
+    let model = test_model(checkerboard_seed_image(4), 2, (10, 8), WrappingType::NoWrap);
+    model.reseed(5);
+    let result = model.outpaint(&image, (2, 3), 2, 10, 100).unwrap();
+    assert_eq!(result.dim(), (8, 10));
+    for ((y, x), &color) in image.indexed_iter() {
+        assert_eq!(result[(y + 2, x + 3)], color);
+    }
+    let white = Color(255, 255, 255, 255);
+    assert!(result.indexed_iter().all(|((y, x), &c)| (c == white) == ((y + x) % 2 == 0)));
+
+    assert!(model.outpaint(&image, (5, 0), 1, 0, 0).is_err());
+
+    let image = knot_seed_image().image_data;
+    let model = test_model(knot_seed_image(), 3, (25, 25), WrappingType::NoWrap);
+    model.reseed(5);
+    let result = model.outpaint(&image, (4, 4), 5, 20, 200).unwrap();
+    for ((y, x), &color) in image.indexed_iter() {
+        assert_eq!(result[(y + 4, x + 4)], color);
+    }
+    assert!(only_learned_patterns(&model, &result));
+}
+
+#[test]
+fn full_palette_indexed_output_test() {
+    let mut seed_image = checkerboard_seed_image(4);
+    let palette: Vec<Color> = (0..256).map(|i| Color(i as u8, i as u8, i as u8, 255)).collect();
+    seed_image.palette = Some(palette.clone());
+    let model = test_model(seed_image, 2, (6, 5), WrappingType::NoWrap);
+    let path = ::std::env::temp_dir().join("rust-wfc-full-palette-output.png");
+    let path = path.to_string_lossy();
+
+    // Undecided cells take the place of the first entry no pixel uses.
+    model.to_indexed_image(&path).unwrap();
+    let loaded = SeedImage::from_file(&path).unwrap();
+    assert_eq!(loaded.palette.unwrap()[0], Color(255, 0, 128, 255));
+
+    model.reseed(2);
+    model.collapse_and_propagate().unwrap();
+    model.to_indexed_image(&path).unwrap();
+    let loaded = SeedImage::from_file(&path).unwrap();
+    assert_eq!(loaded.palette, Some(palette));
+    assert_eq!(loaded.image_data, model.pixels());
+}
+
+#[test]
+fn save_transformed_patterns_test() {
+    // Every orientation of a pattern with no symmetry of its own is a distinct state.
+    let mut seed_image = checkerboard_seed_image(3);
+    seed_image.image_data[(0, 1)] = Color(255, 0, 0, 255);
+    let model = test_model(seed_image, 2, (5, 5), WrappingType::NoWrap);
+    let mut saved = Vec::new();
+    model.save(&mut saved).unwrap();
+    let loaded = OverlappingModel::load(&mut &saved[..]).unwrap();
+    assert_eq!(loaded.states, model.states);
+}
+
@@ -1,3 +1,5 @@
+use error::Error;
+
 use png::{Encoder, Decoder, ColorType, BitDepth, HasParameters, OutputInfo};
 use ndarray::prelude::*;
 
@@ -7,72 +9,279 @@ use std::path::Path;
 use std::io::BufWriter;
 
 
-/// A tuple struct of red, green, blue.
+/// A tuple struct of red, green, blue, alpha. An alpha of 0 is fully transparent.
 #[derive(Copy, Clone, Hash, Debug, PartialEq, PartialOrd, Ord, Eq)]
-pub struct Color(pub u8, pub u8, pub u8);
+pub struct Color(pub u8, pub u8, pub u8, pub u8);
+
+impl Color {
+    pub fn average<I: IntoIterator<Item = Color>>(colors: I) -> Option<Color> {
+        //! Blends colors evenly, or returns None if there are none. Red, green and blue are
+        //! weighted by alpha so that the hidden color of transparent pixels doesn't show.
+        let (mut r, mut g, mut b, mut a, mut count) = (0usize, 0usize, 0usize, 0usize, 0usize);
+        for c in colors {
+            let alpha = c.3 as usize;
+            r += c.0 as usize * alpha;
+            g += c.1 as usize * alpha;
+            b += c.2 as usize * alpha;
+            a += alpha;
+            count += 1;
+        }
+        match (count, a) {
+            (0, _) => None,
+            (_, 0) => Some(Color(0, 0, 0, 0)),
+            _ => Some(Color((r / a) as u8, (g / a) as u8, (b / a) as u8, (a / count) as u8)),
+        }
+    }
+}
 
+/// A sample image loaded from a PNG file, from which an `OverlappingModel` learns its patterns.
 pub struct SeedImage {
     pub image_data: Array2<Color>,
     pub image_info: OutputInfo,
+    /// The palette of an indexed PNG in its original order, including entries the image
+    /// doesn't use. None for other kinds of PNG.
+    pub palette: Option<Vec<Color>>,
 }
 
 impl SeedImage {
-    pub fn from_file(file_path: &str) -> SeedImage {
-        let (image, info) = match SeedImage::load_file(file_path) {
-            Ok(u) => u,
-            Err(t) => {
-                println!("{}", t);
-                panic!();
+    pub fn from_file(file_path: &str) -> Result<SeedImage, Error> {
+        let seed_image = SeedImage::load_file(file_path)?;
+        if seed_image.image_data.len() == 0 {
+            return Err(Error::EmptySample);
+        }
+        Ok(seed_image)
+    }
+
+    pub fn to_file(&self, file_path: &str) -> Result<(), Error> {
+        save_image(&self.image_data, file_path)
+    }
+
+    pub fn to_indexed_file(&self, file_path: &str) -> Result<(), Error> {
+        //! Writes the image as an indexed PNG, using the original palette if the image was
+        //! loaded from one and its distinct colors in sorted order otherwise.
+        let palette = match self.palette {
+            Some(ref palette) => palette.clone(),
+            None => {
+                let mut colors: Vec<Color> = self.image_data.iter().cloned().collect();
+                colors.sort();
+                colors.dedup();
+                colors
             }
         };
-
-        SeedImage {
-            image_data: image,
-            image_info: info,
+        let mut indices = Array2::zeros(self.image_data.dim());
+        for (index, color) in indices.iter_mut().zip(&self.image_data) {
+            *index = palette.iter()
+                .position(|p| p == color)
+                .ok_or(Error::MissingPaletteColor(*color))?;
         }
+        save_indexed_image(&indices, &palette, file_path)
     }
 
-    pub fn to_file(&self, file_path: &str) -> () {
-        let (y, x) = self.image_data.dim();
-        let file_path = Path::new(file_path);
-        let file = File::create(file_path).unwrap();
-        let w = &mut BufWriter::new(file);
-        let mut encoder = Encoder::new(w, x as u32, y as u32);
-        encoder.set(ColorType::RGB).set(BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
+    fn load_file(file_path: &str) -> Result<SeedImage, Error> {
+        //! Decodes any PNG into colors. By default the decoder expands palettes and samples of
+        //! fewer than 8 bits, and strips 16-bit samples down to their high byte, so every
+        //! image arrives as 8-bit grayscale or RGB, with or without alpha.
+        let dec = Decoder::new(File::open(file_path)?);
+        let (info, mut reader) = dec.read_info()?;
+        let (color_type, bit_depth) = (reader.info().color_type, reader.info().bit_depth);
+        let unsupported = Error::UnsupportedFormat(color_type, bit_depth);
+        let to_color: fn(&[u8]) -> Color = match reader.output_color_type() {
+            (ColorType::Grayscale, BitDepth::Eight) => |s| Color(s[0], s[0], s[0], 255),
+            (ColorType::GrayscaleAlpha, BitDepth::Eight) => |s| Color(s[0], s[0], s[0], s[1]),
+            (ColorType::RGB, BitDepth::Eight) => |s| Color(s[0], s[1], s[2], 255),
+            (ColorType::RGBA, BitDepth::Eight) => |s| Color(s[0], s[1], s[2], s[3]),
+            _ => return Err(unsupported),
+        };
+        let samples = reader.output_color_type().0.samples();
+        let palette = match (color_type, reader.info().palette.as_ref()) {
+            (ColorType::Indexed, Some(palette)) => {
+                let trns = reader.info().trns.clone().unwrap_or_default();
+                let alpha = |i| trns.get(i).cloned().unwrap_or(255);
+                Some(palette.chunks(3)
+                    .enumerate()
+                    .map(|(i, rgb)| Color(rgb[0], rgb[1], rgb[2], alpha(i)))
+                    .collect())
+            }
+            _ => None,
+        };
 
-        let mut raw_data = Vec::<u8>::with_capacity(self.image_data.len() * 3);
-        for rgb in self.image_data.iter().cloned() {
-            raw_data.push(rgb.0);
-            raw_data.push(rgb.1);
-            raw_data.push(rgb.2);
+        let mut buf = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut buf)?;
+        let image_data: Vec<_> = buf.chunks(samples).map(to_color).collect();
+        let image_data =
+            Array::from_shape_vec((info.height as usize, info.width as usize), image_data)
+                .map_err(|_| unsupported)?;
+        Ok(SeedImage {
+            image_data,
+            image_info: info,
+            palette,
+        })
+    }
+}
 
+pub fn save_image(image_data: &Array2<Color>, file_path: &str) -> Result<(), Error> {
+    //! Writes the colors out as an 8-bit PNG. The alpha channel is only included when some
+    //! pixel isn't fully opaque.
+    let (y, x) = image_data.dim();
+    let opaque = image_data.iter().all(|c| c.3 == 255);
+    let file_path = Path::new(file_path);
+    let file = File::create(file_path)?;
+    let w = &mut BufWriter::new(file);
+    let mut encoder = Encoder::new(w, x as u32, y as u32);
+    let color_type = if opaque { ColorType::RGB } else { ColorType::RGBA };
+    encoder.set(color_type).set(BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+
+    let mut raw_data = Vec::<u8>::with_capacity(image_data.len() * color_type.samples());
+    for rgba in image_data.iter().cloned() {
+        raw_data.push(rgba.0);
+        raw_data.push(rgba.1);
+        raw_data.push(rgba.2);
+        if !opaque {
+            raw_data.push(rgba.3);
         }
+    }
 
-        writer.write_image_data(&raw_data).unwrap();
+    writer.write_image_data(&raw_data)?;
+    Ok(())
+}
 
+pub fn save_indexed_image(indices: &Array2<usize>,
+                          palette: &[Color],
+                          file_path: &str)
+                          -> Result<(), Error> {
+    //! Writes an 8-bit indexed PNG where each pixel is an index into `palette`, which may
+    //! have at most 256 entries. Alpha is written as a tRNS chunk when some entry isn't fully
+    //! opaque.
+    if palette.len() > 256 {
+        return Err(Error::PaletteTooLarge(palette.len()));
     }
+    let (y, x) = indices.dim();
+    let file = File::create(Path::new(file_path))?;
+    let w = &mut BufWriter::new(file);
+    let mut encoder = Encoder::new(w, x as u32, y as u32);
+    encoder.set(ColorType::Indexed).set(BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
 
+    let rgb: Vec<u8> = palette.iter().flat_map(|c| vec![c.0, c.1, c.2]).collect();
+    writer.write_chunk(*b"PLTE", &rgb)?;
+    if palette.iter().any(|c| c.3 != 255) {
+        let alpha: Vec<u8> = palette.iter().map(|c| c.3).collect();
+        writer.write_chunk(*b"tRNS", &alpha)?;
+    }
 
+    let raw_data: Vec<u8> = indices.iter().map(|&i| i as u8).collect();
+    writer.write_image_data(&raw_data)?;
+    Ok(())
+}
 
-    fn load_file(file_path: &str) -> Result<(Array2<Color>, OutputInfo), String> {
-        let dec = Decoder::new(File::open(file_path).unwrap());
-        let (info, mut reader) = dec.read_info().unwrap();
-        match (info.color_type, info.bit_depth) {
-            (ColorType::RGB, BitDepth::Eight) => {}
-            (j, k) => {
-                return Err(format!("Wrong color type or bit depth. Found color type: {:?} and \
-                                    bit depth: {:?}",
-                                   j,
-                                   k))
-            }
+#[cfg(test)]
+fn write_test_png(name: &str,
+                  color_type: ColorType,
+                  bit_depth: BitDepth,
+                  palette: Option<&[u8]>,
+                  data: &[u8])
+                  -> String {
+    let path = ::std::env::temp_dir().join(format!("rust-wfc-{}.png", name));
+    {
+        let file = File::create(&path).unwrap();
+        let mut encoder = Encoder::new(BufWriter::new(file), 2, 2);
+        encoder.set(color_type).set(bit_depth);
+        let mut writer = encoder.write_header().unwrap();
+        if let Some(palette) = palette {
+            writer.write_chunk(*b"PLTE", palette).unwrap();
         }
+        writer.write_image_data(data).unwrap();
+    }
+    path.to_string_lossy().into_owned()
+}
 
-        let mut buf = vec![0; info.buffer_size()];
-        reader.next_frame(&mut buf).unwrap();
-        let image_data: Vec<_> = buf.chunks(3).map(|s| Color(s[0], s[1], s[2])).collect();
-        let image_data =
-            Array::from_shape_vec((info.height as usize, info.width as usize), image_data).unwrap();
-        Ok((image_data, info))
+#[test]
+fn load_color_types_test() {
+    let check = |name, color_type, bit_depth, palette, data: &[u8], expected: [Color; 4]| {
+        let path = write_test_png(name, color_type, bit_depth, palette, data);
+        let image = SeedImage::from_file(&path).unwrap();
+        assert_eq!(image.image_data.iter().cloned().collect::<Vec<_>>(), expected.to_vec(),
+                   "{}", name);
+    };
+    let (r, g, b) = (Color(255, 0, 0, 255), Color(0, 255, 0, 255), Color(0, 0, 255, 255));
+    let w = Color(255, 255, 255, 255);
+    let gray = |v| Color(v, v, v, 255);
+
+    check("gray8", ColorType::Grayscale, BitDepth::Eight, None,
+          &[0, 100, 200, 255], [gray(0), gray(100), gray(200), gray(255)]);
+    check("gray1", ColorType::Grayscale, BitDepth::One, None,
+          &[0b1000_0000, 0b0100_0000], [gray(255), gray(0), gray(0), gray(255)]);
+    check("grayalpha8", ColorType::GrayscaleAlpha, BitDepth::Eight, None,
+          &[0, 255, 100, 0, 200, 128, 255, 255],
+          [gray(0), Color(100, 100, 100, 0), Color(200, 200, 200, 128), gray(255)]);
+    check("rgba8", ColorType::RGBA, BitDepth::Eight, None,
+          &[255, 0, 0, 255, 0, 255, 0, 0, 0, 0, 255, 9, 255, 255, 255, 255],
+          [r, Color(0, 255, 0, 0), Color(0, 0, 255, 9), w]);
+    check("rgb16", ColorType::RGB, BitDepth::Sixteen, None,
+          &[255, 1, 0, 2, 0, 3, 0, 4, 255, 5, 0, 6, 0, 7, 0, 8, 255, 9, 255, 1, 255, 2, 255, 3],
+          [r, g, b, w]);
+    check("indexed2", ColorType::Indexed, BitDepth::Two, Some(&[255, 0, 0, 0, 255, 0, 0, 0, 255]),
+          &[0b0001_0000, 0b1000_0000], [r, g, b, r]);
+}
+
+#[test]
+fn average_test() {
+    assert_eq!(Color::average(vec![]), None);
+    assert_eq!(Color::average(vec![Color(10, 20, 30, 255), Color(30, 40, 50, 255)]),
+               Some(Color(20, 30, 40, 255)));
+    // A transparent pixel lowers the alpha without tinting the color.
+    assert_eq!(Color::average(vec![Color(200, 100, 0, 255), Color(0, 255, 0, 0)]),
+               Some(Color(200, 100, 0, 127)));
+    assert_eq!(Color::average(vec![Color(1, 2, 3, 0)]), Some(Color(0, 0, 0, 0)));
+}
+
+#[test]
+fn save_transparent_image_test() {
+    let path = ::std::env::temp_dir().join("rust-wfc-transparent.png");
+    let path = path.to_string_lossy();
+    let image = Array2::from_shape_vec((1, 2), vec![Color(1, 2, 3, 0), Color(4, 5, 6, 255)])
+        .unwrap();
+    save_image(&image, &path).unwrap();
+    let loaded = SeedImage::from_file(&path).unwrap();
+    assert_eq!(loaded.image_info.color_type, ColorType::RGBA);
+    assert_eq!(loaded.image_data, image);
+}
+
+#[test]
+fn indexed_round_trip_test() {
+    let palette = [Color(9, 9, 9, 255), Color(200, 0, 0, 128), Color(0, 0, 200, 255)];
+    let indices = Array2::from_shape_vec((2, 2), vec![2, 1, 1, 2]).unwrap();
+    let path = ::std::env::temp_dir().join("rust-wfc-indexed.png");
+    let path = path.to_string_lossy();
+    save_indexed_image(&indices, &palette, &path).unwrap();
+
+    let loaded = SeedImage::from_file(&path).unwrap();
+    assert_eq!(loaded.palette, Some(palette.to_vec()));
+    assert_eq!(loaded.image_data, indices.map(|&i| palette[i]));
+
+    // Writing it back out keeps the original palette order, including the unused entry.
+    loaded.to_indexed_file(&path).unwrap();
+    assert_eq!(SeedImage::from_file(&path).unwrap().palette, Some(palette.to_vec()));
+
+    let mut edited = loaded;
+    edited.image_data[(0, 0)] = Color(1, 2, 3, 255);
+    match edited.to_indexed_file(&path) {
+        Err(Error::MissingPaletteColor(Color(1, 2, 3, 255))) => {}
+        _ => panic!("Expected MissingPaletteColor"),
+    }
+
+    let too_many = vec![Color(0, 0, 0, 255); 257];
+    match save_indexed_image(&indices, &too_many, &path) {
+        Err(Error::PaletteTooLarge(257)) => {}
+        _ => panic!("Expected PaletteTooLarge"),
+    }
+}
+
+#[test]
+fn missing_file_test() {
+    match SeedImage::from_file("./assets/does-not-exist.png") {
+        Err(Error::Io(_)) => {}
+        _ => panic!("Expected an I/O error"),
     }
 }
@@ -0,0 +1,648 @@
+use utils::*;
+pub use wave::{ModelError, Step, WrappingType};
+
+use error::Error;
+use heuristic::Heuristic;
+use listener::Listener;
+use sourceimage::{Color, SeedImage, save_image};
+use wave::{Wave, DIRECTIONS};
+use ndarray::prelude::*;
+use rand::{self, Rng};
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// A hand-authored tile that can be placed in a cell of the output.
+pub struct Tile {
+    pub name: String,
+    pub image_data: Array2<Color>,
+    pub weight: usize,
+}
+
+impl Tile {
+    pub fn from_file(name: &str, file_path: &str, weight: usize) -> Result<Tile, Error> {
+        Ok(Tile {
+            name: name.to_owned(),
+            image_data: SeedImage::from_file(file_path)?.image_data,
+            weight,
+        })
+    }
+}
+
+/// The symmetry class of a tile, named after a letter with the same symmetries. It determines
+/// how many distinct variants the tile has when rotated and reflected.
+///
+/// Variants are numbered as in the reference WFC implementation. Variants 0 to 3 are the tile
+/// turned 0 to 3 quarter turns counter-clockwise, and variants 4 to 7 are those mirrored left to
+/// right. Only the distinct ones are kept. For `T` the tile must be symmetric left to right. For
+/// `L` it must be symmetric about the diagonal from the top right to the bottom left.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TileSymmetry {
+    /// Looks the same after any rotation or reflection.
+    X,
+    /// Has two variants, like a straight line.
+    I,
+    /// Has two variants, like a diagonal line.
+    Backslash,
+    /// Has four variants, like a T junction.
+    T,
+    /// Has four variants, like a corner.
+    L,
+    /// Has no symmetry, so all eight variants are distinct.
+    F,
+}
+
+impl TileSymmetry {
+    pub fn from_name(name: &str) -> Option<TileSymmetry> {
+        match name {
+            "X" => Some(TileSymmetry::X),
+            "I" => Some(TileSymmetry::I),
+            "\\" => Some(TileSymmetry::Backslash),
+            "T" => Some(TileSymmetry::T),
+            "L" => Some(TileSymmetry::L),
+            "F" => Some(TileSymmetry::F),
+            _ => None,
+        }
+    }
+
+    pub fn cardinality(self) -> usize {
+        match self {
+            TileSymmetry::X => 1,
+            TileSymmetry::I | TileSymmetry::Backslash => 2,
+            TileSymmetry::T | TileSymmetry::L => 4,
+            TileSymmetry::F => 8,
+        }
+    }
+
+    pub fn variants<T: Copy>(self, image_data: &Array2<T>) -> Vec<Array2<T>> {
+        //! Returns the images of each variant of a tile drawn as `image_data`.
+        let mut variants: Vec<Array2<T>> = Vec::with_capacity(self.cardinality());
+        for v in 0..self.cardinality() {
+            let image = match v {
+                0 => image_data.to_owned(),
+                1..=3 => Transform::Rotate270.apply(&variants[v - 1]),
+                _ => Transform::FlipHorizontal.apply(&variants[v - 4]),
+            };
+            variants.push(image);
+        }
+        variants
+    }
+
+    fn rotate(self, variant: usize) -> usize {
+        //! The variant that `variant` becomes after a counter-clockwise quarter turn.
+        match self {
+            TileSymmetry::X => variant,
+            TileSymmetry::I | TileSymmetry::Backslash => 1 - variant,
+            TileSymmetry::T | TileSymmetry::L => (variant + 1) % 4,
+            TileSymmetry::F if variant < 4 => (variant + 1) % 4,
+            TileSymmetry::F => 4 + (variant + 3) % 4,
+        }
+    }
+
+    fn reflect(self, variant: usize) -> usize {
+        //! The variant that `variant` becomes after mirroring left to right.
+        match self {
+            TileSymmetry::X | TileSymmetry::I => variant,
+            TileSymmetry::Backslash => 1 - variant,
+            TileSymmetry::T => (4 - variant) % 4,
+            TileSymmetry::L => variant ^ 1,
+            TileSymmetry::F => (variant + 4) % 8,
+        }
+    }
+}
+
+/// Whether a neighbor rule places its two tiles side by side or one above the other.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Adjacency {
+    Horizontal,
+    Vertical,
+}
+
+/// Allows the tile named `first` directly left of (Horizontal) or directly above (Vertical)
+/// the tile named `second`. Tiles that no rule allows next to each other are never neighbours.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NeighborRule {
+    pub first: String,
+    pub second: String,
+    pub adjacency: Adjacency,
+}
+
+impl NeighborRule {
+    pub fn new(first: &str, second: &str, adjacency: Adjacency) -> NeighborRule {
+        NeighborRule {
+            first: first.to_owned(),
+            second: second.to_owned(),
+            adjacency,
+        }
+    }
+}
+
+/// Generates a grid of tiles where every pair of neighbours is allowed by a rule.
+pub struct TiledModel {
+    wave: RefCell<Wave>,
+    tiles: Vec<Tile>,
+    tile_dims: (usize, usize),
+    rng: RefCell<Box<dyn Rng>>,
+}
+
+impl TiledModel {
+    pub fn new(tiles: Vec<Tile>,
+               rules: &[NeighborRule],
+               output_dims: (usize, usize),
+               wrap: WrappingType)
+               -> Result<TiledModel, Error> {
+        //! Builds a model that fills an output of `output_dims` tiles, placing tiles next to
+        //! each other only where `rules` allows it. Every tile must be the same size.
+        let tile_dims = match tiles.first() {
+            Some(t) => t.image_data.dim(),
+            None => return Err(Error::InvalidTileset("a tiled model needs at least one tile".to_owned())),
+        };
+        if let Some(t) = tiles.iter().find(|t| t.image_data.dim() != tile_dims) {
+            return Err(Error::InvalidTileset(format!("tile {} is {:?} but the first tile is {:?}",
+                                                     t.name,
+                                                     t.image_data.dim(),
+                                                     tile_dims)));
+        }
+
+        let propagator = TiledModel::build_propagator(&tiles, rules)?;
+        let weights = tiles.iter().map(|t| t.weight).collect();
+        let (x, y) = output_dims;
+        let wave = Wave::new((y, x), weights, propagator, wrap);
+
+        Ok(TiledModel {
+            wave: RefCell::new(wave),
+            tiles,
+            tile_dims,
+            rng: RefCell::new(Box::new(rand::weak_rng())),
+        })
+    }
+
+    pub fn with_symmetry(tiles: Vec<(Tile, TileSymmetry)>,
+                         rules: &[NeighborRule],
+                         output_dims: (usize, usize),
+                         wrap: WrappingType)
+                         -> Result<TiledModel, Error> {
+        //! Builds a model from tiles with symmetry classes. Each tile is expanded into its
+        //! variants, and every rule is added in all its rotated and reflected forms.
+        //!
+        //! Rules refer to a variant as `name:n`, where a bare `name` means variant 0. The
+        //! variants of the model are named the same way.
+        let (tiles, rules) = TiledModel::expand_symmetries(tiles, rules)?;
+        TiledModel::new(tiles, &rules, output_dims, wrap)
+    }
+
+    pub fn from_rules_file(file_path: &str,
+                           output_dims: (usize, usize),
+                           wrap: WrappingType)
+                           -> Result<TiledModel, Error> {
+        //! Loads a tileset described by a plain text file with one entry per line:
+        //!
+        //! ```text
+        //! # Lines starting with # are comments.
+        //! tile <name> <image path> [weight] [symmetry]
+        //! horizontal <left tile> <right tile>
+        //! vertical <top tile> <bottom tile>
+        //! ```
+        //!
+        //! Image paths are relative to the rules file, and weights default to 1. If any tile
+        //! gives a symmetry class, the file is loaded with `with_symmetry` and tiles without one
+        //! are treated as `X`. Otherwise the rules are used exactly as written.
+        let mut contents = String::new();
+        File::open(file_path)?.read_to_string(&mut contents)?;
+        let directory = Path::new(file_path).parent().unwrap_or_else(|| Path::new(""));
+
+        let mut tiles = Vec::new();
+        let mut rules = Vec::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["tile", name, image, ref options @ ..] if options.len() <= 2 => {
+                    let mut weight = 1;
+                    let mut symmetry = None;
+                    for option in options {
+                        if let Ok(w) = option.parse() {
+                            weight = w;
+                        } else if let Some(s) = TileSymmetry::from_name(option) {
+                            symmetry = Some(s);
+                        } else {
+                            return Err(Error::InvalidTileset(format!("line {}: {} is neither a \
+                                                                      weight nor a symmetry",
+                                                                     line_number + 1,
+                                                                     option)));
+                        }
+                    }
+                    let image = directory.join(image);
+                    tiles.push((Tile::from_file(name, &image.to_string_lossy(), weight)?, symmetry));
+                }
+                ["horizontal", first, second] => {
+                    rules.push(NeighborRule::new(first, second, Adjacency::Horizontal))
+                }
+                ["vertical", first, second] => {
+                    rules.push(NeighborRule::new(first, second, Adjacency::Vertical))
+                }
+                _ => {
+                    return Err(Error::InvalidTileset(format!("line {}: couldn't understand \"{}\"",
+                                                             line_number + 1,
+                                                             line)))
+                }
+            }
+        }
+
+        if tiles.iter().any(|&(_, s)| s.is_some()) {
+            let tiles = tiles.into_iter()
+                .map(|(t, s)| (t, s.unwrap_or(TileSymmetry::X)))
+                .collect();
+            TiledModel::with_symmetry(tiles, &rules, output_dims, wrap)
+        } else {
+            let tiles = tiles.into_iter().map(|(t, _)| t).collect();
+            TiledModel::new(tiles, &rules, output_dims, wrap)
+        }
+    }
+
+    pub fn reseed(&self, seed: u64) {
+        //! Replaces the model's random number generator with one seeded from `seed`.
+        *self.rng.borrow_mut() = Box::new(seeded_rng(seed));
+    }
+
+    pub fn set_rng<R: Rng + 'static>(&self, rng: R) {
+        //! Replaces the model's random number generator with a caller-supplied one.
+        *self.rng.borrow_mut() = Box::new(rng);
+    }
+
+    pub fn set_heuristic<H: Heuristic + 'static>(&self, heuristic: H) {
+        //! Replaces the heuristic used to choose which cell to collapse next.
+        self.wave.borrow_mut().set_heuristic(Box::new(heuristic));
+    }
+
+    pub fn set_listener<L: Listener + 'static>(&self, listener: L) {
+        //! Replaces the listener that is told about progress during generation.
+        self.wave.borrow_mut().set_listener(Box::new(listener));
+    }
+
+    pub fn to_image(&self, file_path: &str) -> Result<(), Error> {
+        //! Writes the image returned by `pixels` to a PNG file at `file_path`.
+        save_image(&self.pixels(), file_path)
+    }
+
+    pub fn step(&self) -> Result<Step, ModelError> {
+        //! Collapses a single cell and propagates the result, so generation can be paused and
+        //! the output inspected between steps. Calling this until it returns Finished or
+        //! Contradiction is equivalent to collapse_and_propagate.
+        let mut rng = self.rng.borrow_mut();
+        self.wave.borrow_mut().step(&mut *rng)
+    }
+
+    pub fn collapse_and_propagate(&self) -> Result<(), ModelError> {
+        let mut rng = self.rng.borrow_mut();
+        self.wave.borrow_mut().collapse_and_propagate(&mut *rng)
+    }
+
+    pub fn collapse_and_propagate_with_backtracking(&self,
+                                                    max_depth: usize,
+                                                    max_backtracks: usize)
+                                                    -> Result<(), ModelError> {
+        let mut rng = self.rng.borrow_mut();
+        self.wave
+            .borrow_mut()
+            .collapse_and_propagate_with_backtracking(&mut *rng, max_depth, max_backtracks)
+    }
+
+    pub fn collapse_and_propagate_with_restarts(&self,
+                                                max_attempts: usize,
+                                                max_depth: usize,
+                                                max_backtracks: usize)
+                                                -> Result<usize, ModelError> {
+        let mut rng = self.rng.borrow_mut();
+        self.wave
+            .borrow_mut()
+            .collapse_and_propagate_with_restarts(&mut *rng, max_attempts, max_depth, max_backtracks)
+    }
+
+    pub fn reset(&self) {
+        self.wave.borrow_mut().reset();
+    }
+
+    pub fn tile_names(&self) -> Array2<Option<&str>> {
+        //! Returns the name of the tile chosen for each cell, or None for cells that are
+        //! undecided or contradictory.
+        let wave = self.wave.borrow();
+        Array2::from_shape_fn(wave.dim(), |position| {
+            let states = wave.possible_states(position);
+            if wave.remaining_states(position) == 1 {
+                states.iter().position(|p| p).map(|t| self.tiles[t].name.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn pixels(&self) -> Array2<Color> {
+        //! Composes the tile images into one image. Undecided cells show the average of the
+        //! tiles they could still be, and contradictions are magenta.
+        let wave = self.wave.borrow();
+        let (y, x) = wave.dim();
+        let (ty, tx) = self.tile_dims;
+        let mut output = Array2::from_elem((y * ty, x * tx), Color(255, 0, 128, 255));
+
+        for i in 0..y {
+            for j in 0..x {
+                let states = wave.possible_states((i, j));
+                let possible: Vec<&Tile> = self.tiles
+                    .iter()
+                    .zip(states.iter())
+                    .filter(|&(_, p)| p)
+                    .map(|(t, _)| t)
+                    .collect();
+                if possible.is_empty() {
+                    continue;
+                }
+                for py in 0..ty {
+                    for px in 0..tx {
+                        let colors = possible.iter().map(|t| t.image_data[(py, px)]);
+                        output[(i * ty + py, j * tx + px)] = Color::average(colors).unwrap();
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    fn expand_symmetries(tiles: Vec<(Tile, TileSymmetry)>,
+                         rules: &[NeighborRule])
+                         -> Result<(Vec<Tile>, Vec<NeighborRule>), Error> {
+        let variant_name = |name: &str, variant: usize| if variant == 0 {
+            name.to_owned()
+        } else {
+            format!("{}:{}", name, variant)
+        };
+        let resolve = |reference: &str| {
+            let (name, variant) = match reference.rfind(':') {
+                Some(i) => {
+                    match reference[i + 1..].parse() {
+                        Ok(v) => (&reference[..i], v),
+                        Err(_) => (reference, 0),
+                    }
+                }
+                None => (reference, 0),
+            };
+            match tiles.iter().find(|&(t, _)| t.name == name) {
+                Some(&(_, symmetry)) if variant < symmetry.cardinality() => {
+                    Ok((name.to_owned(), symmetry, variant))
+                }
+                Some(_) => {
+                    Err(Error::InvalidTileset(format!("tile {} has no variant {}", name, variant)))
+                }
+                None => {
+                    Err(Error::InvalidTileset(format!("neighbor rule refers to unknown tile {}",
+                                                      name)))
+                }
+            }
+        };
+
+        let mut expanded_rules = Vec::new();
+        for rule in rules {
+            let (first, first_symmetry, mut first_variant) = resolve(&rule.first)?;
+            let (second, second_symmetry, mut second_variant) = resolve(&rule.second)?;
+            let mut offset = match rule.adjacency {
+                Adjacency::Horizontal => (0, 1),
+                Adjacency::Vertical => (1, 0),
+            };
+            for turns in 0..4 {
+                for &reflected in &[false, true] {
+                    let (f, s, o) = if reflected {
+                        (first_symmetry.reflect(first_variant),
+                         second_symmetry.reflect(second_variant),
+                         (offset.0, -offset.1))
+                    } else {
+                        (first_variant, second_variant, offset)
+                    };
+                    let (f, s) = (variant_name(&first, f), variant_name(&second, s));
+                    expanded_rules.push(match o {
+                        (0, 1) => NeighborRule::new(&f, &s, Adjacency::Horizontal),
+                        (0, -1) => NeighborRule::new(&s, &f, Adjacency::Horizontal),
+                        (1, 0) => NeighborRule::new(&f, &s, Adjacency::Vertical),
+                        _ => NeighborRule::new(&s, &f, Adjacency::Vertical),
+                    });
+                }
+                if turns < 3 {
+                    first_variant = first_symmetry.rotate(first_variant);
+                    second_variant = second_symmetry.rotate(second_variant);
+                    // A counter-clockwise quarter turn takes right to up and up to left.
+                    offset = (-offset.1, offset.0);
+                }
+            }
+        }
+
+        let mut expanded_tiles = Vec::new();
+        for (tile, symmetry) in tiles {
+            for (v, image_data) in symmetry.variants(&tile.image_data).into_iter().enumerate() {
+                expanded_tiles.push(Tile {
+                    name: variant_name(&tile.name, v),
+                    image_data,
+                    weight: tile.weight,
+                });
+            }
+        }
+        Ok((expanded_tiles, expanded_rules))
+    }
+
+    fn build_propagator(tiles: &[Tile],
+                        rules: &[NeighborRule])
+                        -> Result<Vec<Vec<Vec<usize>>>, Error> {
+        //! Turns the rules into lists of the tiles allowed in each direction of every tile.
+        //! Each rule is added in both directions, so the lists are always symmetric.
+        let index = |name: &str| {
+            tiles.iter()
+                .position(|t| t.name == name)
+                .ok_or_else(|| {
+                    Error::InvalidTileset(format!("neighbor rule refers to unknown tile {}", name))
+                })
+        };
+        let direction = |offset: (isize, isize)| DIRECTIONS.iter().position(|&d| d == offset).unwrap();
+
+        let mut propagator = vec![vec![Vec::new(); tiles.len()]; DIRECTIONS.len()];
+        for rule in rules {
+            let first = index(&rule.first)?;
+            let second = index(&rule.second)?;
+            let (forward, backward) = match rule.adjacency {
+                Adjacency::Horizontal => (direction((0, 1)), direction((0, -1))),
+                Adjacency::Vertical => (direction((1, 0)), direction((-1, 0))),
+            };
+            propagator[forward][first].push(second);
+            propagator[backward][second].push(first);
+        }
+        for allowed in propagator.iter_mut().flat_map(|d| d.iter_mut()) {
+            allowed.sort();
+            allowed.dedup();
+        }
+        Ok(propagator)
+    }
+}
+
+#[cfg(test)]
+fn checkerboard_tiles() -> (Vec<Tile>, Vec<NeighborRule>) {
+    let tile = |name: &str, c: u8| {
+        Tile {
+            name: name.to_owned(),
+            image_data: Array2::from_elem((2, 3), Color(c, c, c, 255)),
+            weight: 1,
+        }
+    };
+    let rules = vec![NeighborRule::new("black", "white", Adjacency::Horizontal),
+                     NeighborRule::new("white", "black", Adjacency::Horizontal),
+                     NeighborRule::new("black", "white", Adjacency::Vertical),
+                     NeighborRule::new("white", "black", Adjacency::Vertical)];
+    (vec![tile("black", 0), tile("white", 255)], rules)
+}
+
+#[test]
+fn tiled_checkerboard_test() {
+    let (tiles, rules) = checkerboard_tiles();
+    let model = TiledModel::new(tiles, &rules, (4, 3), WrappingType::NoWrap).unwrap();
+    assert!(model.collapse_and_propagate().is_ok());
+
+    let names = model.tile_names();
+    for ((i, j), name) in names.indexed_iter() {
+        assert!(name.is_some());
+        assert_eq!(*name, names[(0, (i + j) % 2)]);
+    }
+    let colors = model.pixels();
+    assert_eq!(colors.dim(), (6, 12));
+    assert_eq!(colors[(0, 0)], colors[(1, 2)]);
+    assert!(colors[(0, 0)] != colors[(0, 3)]);
+    assert!(colors[(0, 0)] != colors[(2, 0)]);
+}
+
+#[test]
+fn unknown_tile_test() {
+    let (tiles, mut rules) = checkerboard_tiles();
+    rules.push(NeighborRule::new("black", "grey", Adjacency::Vertical));
+    assert!(TiledModel::new(tiles, &rules, (4, 4), WrappingType::NoWrap).is_err());
+}
+
+#[test]
+fn rules_file_test() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    let directory = env::temp_dir().join("rust-wfc-rules-file-test");
+    fs::create_dir_all(&directory).unwrap();
+    let (tiles, _) = checkerboard_tiles();
+    for t in &tiles {
+        save_image(&t.image_data,
+                   &directory.join(format!("{}.png", t.name)).to_string_lossy())
+            .unwrap();
+    }
+    let rules_path = directory.join("rules.txt");
+    File::create(&rules_path)
+        .unwrap()
+        .write_all(b"# A checkerboard\n\
+                     tile black black.png\n\
+                     tile white white.png 2\n\
+                     horizontal black white\n\
+                     horizontal white black\n\
+                     vertical black white\n\
+                     vertical white black\n")
+        .unwrap();
+
+    let model = TiledModel::from_rules_file(&rules_path.to_string_lossy(),
+                                            (4, 4),
+                                            WrappingType::Torus)
+        .unwrap();
+    assert_eq!(model.tiles[1].weight, 2);
+    assert!(model.collapse_and_propagate().is_ok());
+
+    // A tile with a weight of zero is never chosen while another tile is possible.
+    File::create(&rules_path)
+        .unwrap()
+        .write_all(b"tile black black.png 0\n\
+                     tile white white.png\n\
+                     horizontal black black\n\
+                     horizontal black white\n\
+                     horizontal white black\n\
+                     horizontal white white\n\
+                     vertical black black\n\
+                     vertical black white\n\
+                     vertical white black\n\
+                     vertical white white\n")
+        .unwrap();
+    let model = TiledModel::from_rules_file(&rules_path.to_string_lossy(),
+                                            (4, 4),
+                                            WrappingType::NoWrap)
+        .unwrap();
+    assert_eq!(model.tiles[0].weight, 0);
+    model.reseed(1);
+    assert!(model.collapse_and_propagate().is_ok());
+    assert!(model.tile_names().iter().all(|&name| name == Some("white")));
+}
+
+#[test]
+fn tile_symmetry_variants_test() {
+    let image = |pixels: &[(usize, usize)]| {
+        let mut image = Array2::from_elem((3, 3), 0);
+        for &p in pixels {
+            image[p] = 1;
+        }
+        image
+    };
+    let examples = [(TileSymmetry::X, image(&[(1, 1)])),
+                    (TileSymmetry::I, image(&[(0, 1), (1, 1), (2, 1)])),
+                    (TileSymmetry::Backslash, image(&[(0, 0), (1, 1), (2, 2)])),
+                    (TileSymmetry::T, image(&[(0, 0), (0, 1), (0, 2), (1, 1)])),
+                    (TileSymmetry::L, image(&[(0, 0), (0, 1), (0, 2), (1, 2), (2, 2)])),
+                    (TileSymmetry::F, Array2::from_shape_vec((3, 3), (0..9).collect()).unwrap())];
+
+    for &(symmetry, ref image_data) in &examples {
+        let variants = symmetry.variants(image_data);
+        assert_eq!(variants.len(), symmetry.cardinality());
+        for (v, variant) in variants.iter().enumerate() {
+            assert!(variants.iter().skip(v + 1).all(|other| other != variant));
+            assert_eq!(variants[symmetry.rotate(v)], Transform::Rotate270.apply(variant));
+            assert_eq!(variants[symmetry.reflect(v)], Transform::FlipHorizontal.apply(variant));
+        }
+    }
+}
+
+#[test]
+fn symmetry_rules_test() {
+    let tile = |name: &str| {
+        Tile {
+            name: name.to_owned(),
+            image_data: Array2::from_elem((1, 1), Color(0, 0, 0, 255)),
+            weight: 1,
+        }
+    };
+    let tiles = vec![(tile("line"), TileSymmetry::I), (tile("empty"), TileSymmetry::X)];
+    let rules = [NeighborRule::new("line:1", "line:1", Adjacency::Horizontal),
+                 NeighborRule::new("line", "empty", Adjacency::Horizontal),
+                 NeighborRule::new("empty", "empty", Adjacency::Horizontal)];
+    let (tiles, rules) = TiledModel::expand_symmetries(tiles, &rules).unwrap();
+
+    let names: Vec<&str> = tiles.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, vec!["line", "line:1", "empty"]);
+    // Turning a horizontal line a quarter turn continues a vertical one.
+    assert!(rules.contains(&NeighborRule::new("line", "line", Adjacency::Vertical)));
+    // Mirroring puts empty space on the other side of a vertical line.
+    assert!(rules.contains(&NeighborRule::new("empty", "line", Adjacency::Horizontal)));
+    assert!(rules.contains(&NeighborRule::new("empty", "line:1", Adjacency::Vertical)));
+    assert!(!rules.contains(&NeighborRule::new("line", "line", Adjacency::Horizontal)));
+
+    let model = TiledModel::new(tiles, &rules, (6, 6), WrappingType::NoWrap).unwrap();
+    model.reseed(3);
+    assert!(model.collapse_and_propagate_with_restarts(10, 20, 100).is_ok());
+    let names = model.tile_names();
+    for ((i, j), name) in names.indexed_iter() {
+        match name.unwrap() {
+            "line" => assert!(names.column(j).iter().all(|n| *n == Some("line"))),
+            "line:1" => assert!(names.row(i).iter().all(|n| *n == Some("line:1"))),
+            _ => {}
+        }
+    }
+}
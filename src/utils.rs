@@ -1,27 +1,118 @@
+#![allow(dead_code)]
+
 use ndarray::prelude::*;
 use rand::distributions::{Range, IndependentSample};
-use rand;
+use rand::{Rng, SeedableRng, XorShiftRng};
 use bit_vec::BitVec;
 
-pub fn generate_rotations_and_reflections<T: Copy>(image_data: &Array2<T>) -> Vec<Array2<T>> {
-    let mut output = Vec::with_capacity(8);
-    let input = image_data.to_owned();
-    let input1 = rotate_90_clockwise(&input);
-    let input2 = rotate_90_clockwise(&input1);
-    let input3 = rotate_90_clockwise(&input2);
-    let input4 = reflect(&input);
-    let input5 = rotate_90_clockwise(&input);
-    let input6 = rotate_90_clockwise(&input);
-    let input7 = rotate_90_clockwise(&input);
-    output.push(input);
-    output.push(input1);
-    output.push(input2);
-    output.push(input3);
-    output.push(input4);
-    output.push(input5);
-    output.push(input6);
-    output.push(input7);
-    output
+/// One of the eight symmetries of a square, i.e. a member of the dihedral group D4.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// Mirrors left to right.
+    FlipHorizontal,
+    /// Mirrors top to bottom.
+    FlipVertical,
+    /// Mirrors along the diagonal from the top left to the bottom right.
+    Transpose,
+    /// Mirrors along the diagonal from the top right to the bottom left.
+    AntiTranspose,
+}
+
+impl Transform {
+    pub fn all() -> [Transform; 8] {
+        [Transform::Identity,
+         Transform::Rotate90,
+         Transform::Rotate180,
+         Transform::Rotate270,
+         Transform::FlipHorizontal,
+         Transform::FlipVertical,
+         Transform::Transpose,
+         Transform::AntiTranspose]
+    }
+
+    pub fn apply<T: Copy>(&self, image_data: &Array2<T>) -> Array2<T> {
+        match *self {
+            Transform::Identity => image_data.to_owned(),
+            Transform::Rotate90 => rotate_90_clockwise(image_data),
+            Transform::Rotate180 => rotate_90_clockwise(&rotate_90_clockwise(image_data)),
+            Transform::Rotate270 => {
+                rotate_90_clockwise(&rotate_90_clockwise(&rotate_90_clockwise(image_data)))
+            }
+            Transform::FlipHorizontal => {
+                let mut output = image_data.view();
+                output.invert_axis(Axis(1));
+                output.to_owned()
+            }
+            Transform::FlipVertical => {
+                let mut output = image_data.view();
+                output.invert_axis(Axis(0));
+                output.to_owned()
+            }
+            Transform::Transpose => reflect(image_data),
+            Transform::AntiTranspose => {
+                rotate_90_clockwise(&rotate_90_clockwise(&reflect(image_data)))
+            }
+        }
+    }
+}
+
+/// The set of transforms applied to every pattern of a seed image to augment it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symmetry {
+    transforms: Vec<Transform>,
+}
+
+impl Symmetry {
+    pub fn from_transforms(transforms: &[Transform]) -> Symmetry {
+        //! Uses the given transforms, e.g. [Identity, FlipHorizontal] for samples that have a
+        //! clear up and down but can be mirrored. Identity is always included, so patterns are
+        //! learned as they appear in the sample even if `transforms` is empty.
+        let mut unique = vec![Transform::Identity];
+        for &t in transforms {
+            if !unique.contains(&t) {
+                unique.push(t);
+            }
+        }
+        Symmetry { transforms: unique }
+    }
+
+    pub fn from_count(count: usize) -> Option<Symmetry> {
+        //! Returns a subgroup of D4 with `count` members:
+        //!
+        //! * 1: the patterns as they appear in the sample.
+        //! * 2: patterns and their left to right mirror images.
+        //! * 4: patterns in all four rotations.
+        //! * 8: patterns in all four rotations and their mirror images.
+        use self::Transform::*;
+        let transforms: &[Transform] = match count {
+            1 => &[Identity],
+            2 => &[Identity, FlipHorizontal],
+            4 => &[Identity, Rotate90, Rotate180, Rotate270],
+            8 => &Transform::all(),
+            _ => return None,
+        };
+        Some(Symmetry::from_transforms(transforms))
+    }
+
+    pub fn all() -> Symmetry {
+        Symmetry::from_transforms(&Transform::all())
+    }
+
+    pub fn none() -> Symmetry {
+        Symmetry::from_transforms(&[Transform::Identity])
+    }
+
+    pub fn transforms(&self) -> &[Transform] {
+        &self.transforms
+    }
+
+    pub fn apply<T: Copy>(&self, image_data: &Array2<T>) -> Vec<Array2<T>> {
+        self.transforms.iter().map(|t| t.apply(image_data)).collect()
+    }
 }
 
 pub fn rotate_90_clockwise<T: Copy>(image_data: &Array2<T>) -> Array2<T> {
@@ -34,23 +125,26 @@ pub fn reflect<T: Copy>(image_data: &Array2<T>) -> Array2<T> {
     image_data.t().to_owned()
 }
 
-pub fn masked_weighted_choice<T, M>(input: &[(T, usize)], mask: &M) -> Option<usize>
-    where for<'a> &'a M: IntoIterator<Item = bool>
+pub fn masked_weighted_choice<M, R>(weights: &[usize], mask: &M, rng: &mut R) -> Option<usize>
+    where for<'a> &'a M: IntoIterator<Item = bool>,
+          R: Rng
 {
-    /// Returns an index from the slice of (T, u) where u is the integer weight, i.e.
-    /// [('a', 3), ('b', 1), ('c', 1)] returns 0 (the index of 'a') with probability 3/5
+    //! Returns an index from the slice of integer weights, i.e. [3, 1, 1] returns 0 with
+    //! probability 3/5. Returns None if every unmasked weight is zero.
 
-    let total: usize = input.iter()
-        .map(|&(_, u)| u)
-        .zip(mask.into_iter())
+    let total: usize = weights.iter()
+        .cloned()
+        .zip(mask)
         .filter(|&(_, m)| m)
         .map(|(u, _)| u)
         .sum();
-    let between = Range::new(0, total);
-    let mut rng = rand::thread_rng();
-    let mut choice: usize = between.ind_sample(&mut rng);
+    if total == 0 {
+        return None;
+    }
+    let between = Range::new(0, total as u64);
+    let mut choice = between.ind_sample(rng) as usize;
 
-    for ((index, u), mask) in input.iter().map(|&(_, u)| u).enumerate().zip(mask.into_iter()) {
+    for ((index, u), mask) in weights.iter().cloned().enumerate().zip(mask) {
         if mask {
             if choice < u {
                 return Some(index);
@@ -61,6 +155,33 @@ pub fn masked_weighted_choice<T, M>(input: &[(T, usize)], mask: &M) -> Option<us
     None
 }
 
+pub fn seeded_rng(seed: u64) -> XorShiftRng {
+    //! Builds a generator whose output depends only on `seed`, on every platform.
+    let low = seed as u32;
+    let high = (seed >> 32) as u32;
+    // XorShiftRng must not be seeded with all zeroes.
+    XorShiftRng::from_seed([low, high, low ^ 0x9E37_79B9, high ^ 0x7F4A_7C15])
+}
+
+pub fn rng_state(rng: &XorShiftRng) -> [u32; 4] {
+    //! Returns the seed that rebuilds `rng` exactly as it is now. The generator keeps its
+    //! state private, but its next four outputs determine it and every step can be undone.
+    let mut copy = rng.clone();
+    let next: Vec<u32> = (0..4).map(|_| copy.next_u32()).collect();
+    // Each output is `before ^ (before >> 19) ^ t`, where `t = u ^ (u >> 8)` and
+    // `u = v ^ (v << 11)` for the state word `v` that was shifted out.
+    let unshift = |result: u32, shift: &dyn Fn(u32) -> u32| {
+        (0..32).fold(result, |v, _| result ^ shift(v))
+    };
+    let shifted_out = |before: u32, output: u32| {
+        let t = output ^ before ^ (before >> 19);
+        let u = unshift(t, &|v| v >> 8);
+        unshift(u, &|v| v << 11)
+    };
+    let w = shifted_out(next[2], next[3]);
+    [shifted_out(w, next[0]), shifted_out(next[0], next[1]), shifted_out(next[1], next[2]), w]
+}
+
 pub fn mass_intersect(sets: Vec<BitVec>) -> Option<BitVec> {
     let mut output = None;
     for bv in sets {
@@ -77,6 +198,73 @@ pub fn mass_intersect(sets: Vec<BitVec>) -> Option<BitVec> {
 }
 
 
+#[test]
+fn rotations_and_reflections_test() {
+    let input = Array2::from_shape_vec((2, 3), vec![1, 2, 3, 4, 5, 6]).unwrap();
+    let output = Symmetry::all().apply(&input);
+    assert_eq!(output.len(), 8);
+    for (i, a) in output.iter().enumerate() {
+        for b in output.iter().skip(i + 1) {
+            assert!(a != b);
+        }
+    }
+}
+
+#[test]
+fn transform_test() {
+    let input = Array2::from_shape_vec((2, 2), vec![1, 2, 3, 4]).unwrap();
+    let expect = |v: Vec<usize>| Array2::from_shape_vec((2, 2), v).unwrap();
+    assert_eq!(Transform::Rotate90.apply(&input), expect(vec![3, 1, 4, 2]));
+    assert_eq!(Transform::Rotate270.apply(&input), expect(vec![2, 4, 1, 3]));
+    assert_eq!(Transform::FlipHorizontal.apply(&input), expect(vec![2, 1, 4, 3]));
+    assert_eq!(Transform::FlipVertical.apply(&input), expect(vec![3, 4, 1, 2]));
+    assert_eq!(Transform::Transpose.apply(&input), expect(vec![1, 3, 2, 4]));
+    assert_eq!(Transform::AntiTranspose.apply(&input), expect(vec![4, 2, 3, 1]));
+}
+
+#[test]
+fn symmetry_from_count_test() {
+    assert_eq!(Symmetry::from_count(1), Some(Symmetry::none()));
+    assert_eq!(Symmetry::from_count(8), Some(Symmetry::all()));
+    assert_eq!(Symmetry::from_count(4).unwrap().transforms().len(), 4);
+    assert_eq!(Symmetry::from_count(3), None);
+    assert_eq!(Symmetry::from_transforms(&[]), Symmetry::none());
+    assert_eq!(Symmetry::from_transforms(&[Transform::Rotate90]).transforms(),
+               &[Transform::Identity, Transform::Rotate90]);
+}
+
+#[test]
+fn masked_weighted_choice_seeded_test() {
+    let weights = [3, 1, 1, 5];
+    let mask = BitVec::from_bytes(&[0b11010000]);
+    let choices = |seed| {
+        let mut rng = seeded_rng(seed);
+        (0..20).map(|_| masked_weighted_choice(&weights, &mask, &mut rng).unwrap()).collect::<Vec<_>>()
+    };
+    let first = choices(7);
+    assert_eq!(first, choices(7));
+    assert!(first.iter().all(|&i| i != 2));
+}
+
+#[test]
+fn masked_weighted_choice_zero_weight_test() {
+    let mut rng = seeded_rng(1);
+    let mask = BitVec::from_bytes(&[0b01100000]);
+    assert_eq!(masked_weighted_choice(&[3, 0, 0, 5], &mask, &mut rng), None);
+    assert_eq!(masked_weighted_choice(&[3, 1, 1], &BitVec::from_elem(3, false), &mut rng),
+               None);
+}
+
+#[test]
+fn rng_state_test() {
+    let mut rng = seeded_rng(5);
+    for _ in 0..3 {
+        let mut copy = XorShiftRng::from_seed(rng_state(&rng));
+        assert_eq!((0..10).map(|_| copy.next_u32()).collect::<Vec<_>>(),
+                   (0..10).map(|_| rng.next_u32()).collect::<Vec<_>>());
+    }
+}
+
 #[test]
 fn mass_intersect_empty_test() {
     let test_vec = vec![];
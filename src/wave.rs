@@ -0,0 +1,876 @@
+use utils::*;
+use heuristic::{Heuristic, MinEntropy};
+use listener::Listener;
+
+use bit_vec::BitVec;
+use ndarray::prelude::*;
+use rand::Rng;
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::f64;
+
+/// Offsets from a cell to its four neighbours as (row, column). Opposite directions are two
+/// apart, see `opposite`.
+pub const DIRECTIONS: [(isize, isize); 4] = [(-1, 0), (0, 1), (1, 0), (0, -1)];
+
+pub fn opposite(direction: usize) -> usize {
+    (direction + 2) % 4
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum ModelError {
+    NoValidStates((usize, usize)),
+    UnexpectedNaN((usize, usize)),
+    AllStatesDecided,
+    BacktrackLimitReached,
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModelError::NoValidStates((row, column)) => {
+                write!(f, "no valid states left at row {}, column {}", row, column)
+            }
+            ModelError::UnexpectedNaN((row, column)) => {
+                write!(f, "entropy is NaN at row {}, column {}", row, column)
+            }
+            ModelError::AllStatesDecided => write!(f, "every cell is already decided"),
+            ModelError::BacktrackLimitReached => write!(f, "reached the backtracking limit"),
+        }
+    }
+}
+
+impl error::Error for ModelError {}
+
+/// What a single call to `step` did.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Step {
+    /// The cell at this position was collapsed to this state and the change propagated.
+    Collapsed((usize, usize), usize),
+    /// Every cell is decided, so there is nothing left to do.
+    Finished,
+    /// The cell at this position has no possible states left. The wave must be reset before
+    /// generation can continue.
+    Contradiction((usize, usize)),
+}
+
+/// How coordinates that fall off the edge of a grid are treated.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WrappingType {
+    /// Coordinates outside the grid are ignored.
+    NoWrap,
+    /// Coordinates wrap around to the opposite edge, producing a tileable output.
+    Torus,
+}
+
+#[derive(Debug, Clone)]
+struct UncertainCell {
+    possible_states: BitVec,
+    remaining: usize,
+    /// For every state and direction, the number of states still possible in the neighbour
+    /// on the opposite side that allow this state. Once any of these drop to zero the state
+    /// is no longer supported and gets banned.
+    compatible: Vec<[u32; 4]>,
+    sum_of_weights: f64,
+    sum_of_weight_log_weights: f64,
+    entropy: f64,
+}
+
+impl UncertainCell {
+    pub fn new(num_states: usize) -> UncertainCell {
+        UncertainCell {
+            possible_states: BitVec::from_elem(num_states, true),
+            remaining: num_states,
+            compatible: vec![[0; 4]; num_states],
+            sum_of_weights: 0.,
+            sum_of_weight_log_weights: 0.,
+            entropy: 0.,
+        }
+    }
+
+    pub fn entropy(&self) -> Option<f64> {
+        //! Returns the Shannon entropy of the states still permitted, weighted by frequency, or
+        //! None if no states are permitted. If every permitted state has a weight of zero they
+        //! are treated as equally likely.
+        match self.remaining {
+            0 => None,
+            1 => Some(0.),
+            n if self.sum_of_weights == 0. => Some((n as f64).ln()),
+            _ => Some(self.entropy),
+        }
+    }
+
+    fn update_weights(&mut self, weight: f64, weight_log_weight: f64) {
+        //! Adds a state's weight to the running sums, or removes it if the weight is negative,
+        //! then recomputes the entropy from them. For weights w with total W the entropy is
+        //! -sum(w/W * ln(w/W)), which rearranges to ln(W) - sum(w * ln(w)) / W.
+        self.sum_of_weights += weight;
+        self.sum_of_weight_log_weights += weight_log_weight;
+        self.entropy = self.sum_of_weights.ln() -
+                       self.sum_of_weight_log_weights / self.sum_of_weights;
+    }
+}
+
+/// The grid of cells being solved along with everything needed to observe and propagate.
+///
+/// Each cell holds the set of states (patterns or tiles) it may still take. `propagator[d][s]`
+/// lists the states allowed in the neighbour at `DIRECTIONS[d]` of a cell in state `s`.
+/// Removing a state from a cell decrements the support counters of the states it allowed in
+/// the neighbouring cells, and any state left without support is removed in turn.
+///
+/// The entropy of each cell is kept up to date as states are removed. The cell to collapse
+/// next is chosen by a `Heuristic`, which is told about every cell that changes.
+pub struct Wave {
+    cells: Array2<UncertainCell>,
+    weights: Vec<usize>,
+    weight_log_weights: Vec<f64>,
+    heuristic: Option<Box<dyn Heuristic>>,
+    heuristic_ready: bool,
+    restored_noise: Option<Array2<f64>>,
+    changed: Vec<(usize, usize)>,
+    listener: Option<Box<dyn Listener>>,
+    propagator: Vec<Vec<Vec<usize>>>,
+    wrap: WrappingType,
+    pending: Vec<((usize, usize), usize)>,
+    contradiction: Option<(usize, usize)>,
+    record_trail: bool,
+    trail: VecDeque<((usize, usize), usize)>,
+    trail_start: usize,
+    constraints: Vec<((usize, usize), BitVec)>,
+}
+
+impl Wave {
+    pub fn new(dims: (usize, usize),
+               weights: Vec<usize>,
+               propagator: Vec<Vec<Vec<usize>>>,
+               wrap: WrappingType)
+               -> Wave {
+        //! Builds a wave with `dims` rows and columns where every state is possible.
+        debug_assert_eq!(propagator.len(), DIRECTIONS.len());
+        let num_states = weights.len();
+        let mut cell_data = Vec::<UncertainCell>::with_capacity(dims.0 * dims.1);
+        for _ in 0..(dims.0 * dims.1) {
+            cell_data.push(UncertainCell::new(num_states));
+        }
+
+        // w * ln(w) tends to 0 as w does, but evaluates to NaN at 0.
+        let weight_log_weights = weights.iter()
+            .map(|&w| if w == 0 { 0. } else { w as f64 * (w as f64).ln() })
+            .collect();
+
+        let mut wave = Wave {
+            cells: Array::from_shape_vec(dims, cell_data).unwrap(),
+            weights,
+            weight_log_weights,
+            heuristic: Some(Box::new(MinEntropy::new())),
+            heuristic_ready: false,
+            restored_noise: None,
+            changed: Vec::new(),
+            listener: None,
+            propagator,
+            wrap,
+            pending: Vec::new(),
+            contradiction: None,
+            record_trail: false,
+            trail: VecDeque::new(),
+            trail_start: 0,
+            constraints: Vec::new(),
+        };
+        wave.reset();
+        wave
+    }
+
+    pub fn dim(&self) -> (usize, usize) {
+        self.cells.dim()
+    }
+
+    pub fn cells(&self) -> Vec<(usize, usize)> {
+        //! Returns the position of every cell, row by row.
+        let (y, x) = self.dim();
+        (0..y * x).map(|i| (i / x, i % x)).collect()
+    }
+
+    pub fn wrap(&self) -> WrappingType {
+        self.wrap
+    }
+
+    pub fn possible_states(&self, position: (usize, usize)) -> &BitVec {
+        &self.cells[position].possible_states
+    }
+
+    pub fn remaining_states(&self, position: (usize, usize)) -> usize {
+        self.cells[position].remaining
+    }
+
+    pub fn entropy(&self, position: (usize, usize)) -> Option<f64> {
+        self.cells[position].entropy()
+    }
+
+    pub fn set_heuristic(&mut self, heuristic: Box<dyn Heuristic>) {
+        //! Replaces the heuristic used to choose which cell to collapse next.
+        self.heuristic = Some(heuristic);
+        self.heuristic_ready = false;
+        self.changed.clear();
+    }
+
+    pub fn set_listener(&mut self, listener: Box<dyn Listener>) {
+        //! Replaces the listener that is told about every change to the wave.
+        self.listener = Some(listener);
+    }
+
+    pub fn heuristic_noise(&self) -> Option<&Array2<f64>> {
+        //! Returns the noise the heuristic uses to break ties, if it has drawn any since it
+        //! last started afresh.
+        if self.heuristic_ready {
+            self.heuristic.as_ref().and_then(|h| h.noise())
+        } else {
+            self.restored_noise.as_ref()
+        }
+    }
+
+    pub fn restore_heuristic_noise(&mut self, noise: Array2<f64>) {
+        //! Makes the heuristic start afresh with `noise`, as returned by heuristic_noise,
+        //! instead of drawing new noise the next time it chooses a cell. This also applies to
+        //! a heuristic set after this call, but not after the next reset.
+        self.restored_noise = Some(noise);
+        self.heuristic_ready = false;
+        self.changed.clear();
+    }
+
+    pub fn reset(&mut self) {
+        //! Makes every state possible in every cell again.
+        let num_states = self.weights.len();
+        let initial: Vec<[u32; 4]> = (0..num_states)
+            .map(|s| {
+                let mut counts = [0; 4];
+                for (d, count) in counts.iter_mut().enumerate() {
+                    *count = self.propagator[opposite(d)][s].len() as u32;
+                }
+                counts
+            })
+            .collect();
+        let sum_of_weights = self.weights.iter().sum::<usize>() as f64;
+        let sum_of_weight_log_weights = self.weight_log_weights.iter().sum();
+        for cell in self.cells.iter_mut() {
+            cell.possible_states.set_all();
+            cell.remaining = num_states;
+            cell.compatible.clone_from(&initial);
+            cell.sum_of_weights = 0.;
+            cell.sum_of_weight_log_weights = 0.;
+            cell.update_weights(sum_of_weights, sum_of_weight_log_weights);
+        }
+        self.heuristic_ready = false;
+        self.restored_noise = None;
+        self.changed.clear();
+        self.pending.clear();
+        self.contradiction = None;
+        self.trail.clear();
+        self.trail_start = 0;
+
+        // States that allow nothing at all next to them can never appear beside another cell.
+        let (y, x) = self.dim();
+        for position in (0..y * x).map(|i| (i / x, i % x)) {
+            for d in 0..DIRECTIONS.len() {
+                if self.neighbour(position, d).is_none() {
+                    continue;
+                }
+                for s in 0..num_states {
+                    if self.propagator[d][s].is_empty() {
+                        self.ban(position, s);
+                    }
+                }
+            }
+        }
+        for (position, allowed) in mem::take(&mut self.constraints) {
+            for s in (0..num_states).filter(|&s| !allowed[s]) {
+                self.ban(position, s);
+            }
+            self.constraints.push((position, allowed));
+        }
+        let _ = self.propagate();
+    }
+
+    pub fn constrain(&mut self,
+                     position: (usize, usize),
+                     allowed: &BitVec)
+                     -> Result<(), ModelError> {
+        //! Limits a cell to the `allowed` states and propagates the change. The constraint is
+        //! kept across resets and restarts. If it leaves any cell without states the wave is
+        //! restored to how it was, the constraint is dropped and NoValidStates is returned.
+        //! A wave that is already in contradiction is left untouched and the constraint is
+        //! refused the same way.
+        self.constrain_all(&[(position, allowed.clone())]).map_err(|(_, e)| e)
+    }
+
+    pub fn constrain_all(&mut self,
+                         constraints: &[((usize, usize), BitVec)])
+                         -> Result<(), (usize, ModelError)> {
+        //! Applies several constraints in order as `constrain` does, all or nothing. If one of
+        //! them fails the wave is restored to how it was before the first, none of them are
+        //! kept, and the index of the failing constraint is returned with the error.
+        if let Some(contradiction) = self.contradiction {
+            return Err((0, ModelError::NoValidStates(contradiction)));
+        }
+        let record_trail = mem::replace(&mut self.record_trail, true);
+        let trail_len = self.trail.len();
+        let mut result = Ok(());
+        for (i, &(position, ref allowed)) in constraints.iter().enumerate() {
+            for s in (0..self.weights.len()).filter(|&s| !allowed[s]) {
+                self.ban(position, s);
+            }
+            if let Err(e) = self.propagate() {
+                result = Err((i, e));
+                break;
+            }
+        }
+        if result.is_err() {
+            self.undo_to(self.trail_start + trail_len);
+        } else {
+            self.trail.truncate(trail_len);
+            self.constraints.extend_from_slice(constraints);
+        }
+        self.record_trail = record_trail;
+        result
+    }
+
+    pub fn constraints(&self) -> &[((usize, usize), BitVec)] {
+        &self.constraints
+    }
+
+    pub fn clear_constraints(&mut self) {
+        //! Forgets every constraint. Cells keep their current states until the next reset.
+        self.constraints.clear();
+    }
+
+    pub fn ban(&mut self, position: (usize, usize), state: usize) {
+        //! Removes a single state from a cell. The removal is only passed on to other cells by
+        //! the next call to propagate.
+        let cell = &mut self.cells[position];
+        if !cell.possible_states.get(state).unwrap() {
+            return;
+        }
+        cell.possible_states.set(state, false);
+        cell.remaining -= 1;
+        cell.update_weights(-(self.weights[state] as f64), -self.weight_log_weights[state]);
+        if let Some(ref mut listener) = self.listener {
+            listener.state_removed(position, state);
+        }
+        if cell.remaining == 0 && self.contradiction.is_none() {
+            self.contradiction = Some(position);
+            if let Some(ref mut listener) = self.listener {
+                listener.contradiction(position);
+            }
+        }
+        if self.heuristic_ready && self.changed.last() != Some(&position) {
+            self.changed.push(position);
+        }
+        self.pending.push((position, state));
+        if self.record_trail {
+            self.trail.push_back((position, state));
+        }
+    }
+
+    pub fn propagate(&mut self) -> Result<(), ModelError> {
+        //! Removes every state that has lost the support of one of its neighbours, until
+        //! nothing else changes. Returns NoValidStates if any cell is left without states.
+        while let Some((position, state)) = self.pending.pop() {
+            for d in 0..DIRECTIONS.len() {
+                let neighbour = match self.neighbour(position, d) {
+                    Some(n) => n,
+                    None => continue,
+                };
+                for i in 0..self.propagator[d][state].len() {
+                    let other = self.propagator[d][state][i];
+                    let count = {
+                        let count = &mut self.cells[neighbour].compatible[other][d];
+                        *count -= 1;
+                        *count
+                    };
+                    if count == 0 {
+                        self.ban(neighbour, other);
+                    }
+                }
+            }
+        }
+        match self.contradiction {
+            Some(u) => Err(ModelError::NoValidStates(u)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn observe<R: Rng>(&mut self, position: (usize, usize), rng: &mut R) -> usize {
+        //! Bans all but a single state of the cell, randomly chosen from the states still
+        //! permitted and weighted by their frequency. Returns the index of the chosen state.
+        //! If every permitted state has a weight of zero they are all equally likely.
+        let possible_states = &self.cells[position].possible_states;
+        let chosen_state = masked_weighted_choice(&self.weights, possible_states, rng)
+            .or_else(|| masked_weighted_choice(&vec![1; self.weights.len()], possible_states, rng))
+            .unwrap_or(0);
+        for state in 0..self.weights.len() {
+            if state != chosen_state {
+                self.ban(position, state);
+            }
+        }
+        chosen_state
+    }
+
+    pub fn step<R: Rng>(&mut self, rng: &mut R) -> Result<Step, ModelError> {
+        //! Collapses the next cell chosen by the heuristic and propagates the result, so that
+        //! generation can be driven one collapse at a time. There is no backtracking. Only
+        //! unexpected failures such as UnexpectedNaN are returned as errors. The listener is
+        //! told the wave has finished whenever a step doesn't collapse a cell cleanly.
+        let result = match self.next_cell(rng) {
+            Ok(position) => {
+                self.notify(|l| l.cell_chosen(position));
+                let state = self.observe(position, rng);
+                self.notify(|l| l.cell_collapsed(position, state));
+                match self.propagate() {
+                    Ok(()) => return Ok(Step::Collapsed(position, state)),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(ModelError::AllStatesDecided) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.notify(|l| l.finished(&result));
+        match result {
+            Ok(()) => Ok(Step::Finished),
+            Err(ModelError::NoValidStates(u)) => Ok(Step::Contradiction(u)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn collapse_and_propagate<R: Rng>(&mut self, rng: &mut R) -> Result<(), ModelError> {
+        self.collapse_and_propagate_with_backtracking(rng, 0, 0)
+    }
+
+    pub fn collapse_and_propagate_with_backtracking<R: Rng>(&mut self,
+                                                            rng: &mut R,
+                                                            max_depth: usize,
+                                                            max_backtracks: usize)
+                                                            -> Result<(), ModelError> {
+        //! Every state removed after a collapse is kept on a trail so the collapse can be
+        //! undone. When a contradiction is found the most recent collapse is undone and the
+        //! state it chose is banned from that cell before carrying on. Only the last
+        //! `max_depth` collapses can be undone, and after `max_backtracks` undos this gives up
+        //! with BacktrackLimitReached.
+        self.collapse_and_propagate_observed(rng, max_depth, max_backtracks, |_| {})
+    }
+
+    pub fn collapse_and_propagate_observed<R, F>(&mut self,
+                                                 rng: &mut R,
+                                                 max_depth: usize,
+                                                 max_backtracks: usize,
+                                                 mut after_collapse: F)
+                                                 -> Result<(), ModelError>
+        where R: Rng,
+              F: FnMut(&Wave)
+    {
+        //! The same as collapse_and_propagate_with_backtracking, but calls `after_collapse`
+        //! each time a collapse has been propagated without a contradiction, including any
+        //! backtracking it needed.
+        use self::ModelError::*;
+        let mut history = VecDeque::<(usize, (usize, usize), usize)>::new();
+        let mut backtracks = 0;
+        self.record_trail = max_depth > 0;
+
+        loop {
+            let mut result = match self.next_cell(rng) {
+                Ok(collapse_point) => {
+                    self.notify(|l| l.cell_chosen(collapse_point));
+                    if history.len() == max_depth && max_depth > 0 {
+                        history.pop_front();
+                        self.forget_trail_before(history.front().map(|h| h.0));
+                    }
+                    let trail_len = self.trail_start + self.trail.len();
+                    let chosen_state = self.observe(collapse_point, rng);
+                    self.notify(|l| l.cell_collapsed(collapse_point, chosen_state));
+                    if max_depth > 0 {
+                        history.push_back((trail_len, collapse_point, chosen_state));
+                    }
+                    self.propagate()
+                }
+                Err(AllStatesDecided) => {
+                    self.record_trail = false;
+                    self.notify(|l| l.finished(&Ok(())));
+                    return Ok(());
+                }
+                Err(e) => Err(e),
+            };
+
+            while let Err(NoValidStates(_)) = result {
+                let (trail_len, collapse_point, chosen_state) = match history.pop_back() {
+                    Some(h) => h,
+                    None => break,
+                };
+                if backtracks == max_backtracks {
+                    result = Err(BacktrackLimitReached);
+                    break;
+                }
+                backtracks += 1;
+                self.undo_to(trail_len);
+                self.ban(collapse_point, chosen_state);
+                result = self.propagate();
+            }
+
+            if let Err(e) = result {
+                self.record_trail = false;
+                self.notify(|l| l.finished(&Err(e)));
+                return Err(e);
+            }
+            after_collapse(self);
+        }
+    }
+
+    pub fn collapse_and_propagate_with_restarts<R: Rng>(&mut self,
+                                                        rng: &mut R,
+                                                        max_attempts: usize,
+                                                        max_depth: usize,
+                                                        max_backtracks: usize)
+                                                        -> Result<usize, ModelError> {
+        //! Runs collapse_and_propagate_with_backtracking, resetting the wave and trying again
+        //! whenever it fails, up to `max_attempts` times. Returns the number of the attempt
+        //! that succeeded, counting from 1, or the error from the final attempt.
+        let mut attempt = 1;
+        loop {
+            match self.collapse_and_propagate_with_backtracking(rng, max_depth, max_backtracks) {
+                Ok(()) => return Ok(attempt),
+                Err(e) => {
+                    if attempt >= max_attempts {
+                        return Err(e);
+                    }
+                }
+            }
+            self.reset();
+            attempt += 1;
+        }
+    }
+
+    fn notify<F: FnOnce(&mut dyn Listener)>(&mut self, event: F) {
+        if let Some(ref mut listener) = self.listener {
+            event(&mut **listener);
+        }
+    }
+
+    fn undo_to(&mut self, trail_len: usize) {
+        //! Restores every state removed since the trail was `trail_len` entries long, in the
+        //! reverse order to how they were removed, along with the support they gave.
+        while self.trail_start + self.trail.len() > trail_len {
+            let (position, state) = self.trail.pop_back().unwrap();
+            {
+                let cell = &mut self.cells[position];
+                cell.possible_states.set(state, true);
+                cell.remaining += 1;
+                cell.update_weights(self.weights[state] as f64, self.weight_log_weights[state]);
+            }
+            self.notify(|l| l.state_restored(position, state));
+            if self.heuristic_ready && self.changed.last() != Some(&position) {
+                self.changed.push(position);
+            }
+            for d in 0..DIRECTIONS.len() {
+                let neighbour = match self.neighbour(position, d) {
+                    Some(n) => n,
+                    None => continue,
+                };
+                for i in 0..self.propagator[d][state].len() {
+                    let other = self.propagator[d][state][i];
+                    self.cells[neighbour].compatible[other][d] += 1;
+                }
+            }
+        }
+        self.contradiction = None;
+    }
+
+    fn forget_trail_before(&mut self, trail_len: Option<usize>) {
+        //! Drops trail entries that can no longer be undone.
+        let trail_len = trail_len.unwrap_or(self.trail_start + self.trail.len());
+        let drop = trail_len - self.trail_start;
+        self.trail.drain(..drop);
+        self.trail_start = trail_len;
+    }
+
+    fn next_cell<R: Rng>(&mut self, rng: &mut R) -> Result<(usize, usize), ModelError> {
+        //! Asks the heuristic for the next cell to collapse, after telling it about every cell
+        //! that has changed since it was last asked.
+        if let Some(u) = self.contradiction {
+            return Err(ModelError::NoValidStates(u));
+        }
+
+        let mut heuristic = self.heuristic.take().unwrap();
+        if !self.heuristic_ready {
+            match self.restored_noise.take() {
+                Some(noise) => heuristic.restore(self, noise, rng),
+                None => heuristic.reset(self, rng),
+            }
+            self.heuristic_ready = true;
+        } else {
+            for position in mem::take(&mut self.changed) {
+                heuristic.cell_changed(self, position);
+            }
+        }
+        let next_cell = heuristic.next_cell(self, rng);
+        self.heuristic = Some(heuristic);
+
+        match next_cell {
+            None => Err(ModelError::AllStatesDecided),
+            Some(u) if self.entropy(u).is_some_and(f64::is_nan) => {
+                Err(ModelError::UnexpectedNaN(u))
+            }
+            Some(u) => Ok(u),
+        }
+    }
+
+    pub fn neighbour(&self, position: (usize, usize), direction: usize) -> Option<(usize, usize)> {
+        //! Returns the cell next to position in the given direction, or None if it falls
+        //! outside a non-wrapping grid.
+        let (dy, dx) = DIRECTIONS[direction];
+        let coord = (position.0 as isize + dy, position.1 as isize + dx);
+        let (safe_y, safe_x) = (self.cells.dim().0 as isize, self.cells.dim().1 as isize);
+        match self.wrap {
+            WrappingType::NoWrap => {
+                if coord.0 >= 0 && coord.0 < safe_y && coord.1 >= 0 && coord.1 < safe_x {
+                    Some((coord.0 as usize, coord.1 as usize))
+                } else {
+                    None
+                }
+            }
+            WrappingType::Torus => {
+                Some((coord.0.rem_euclid(safe_y) as usize, coord.1.rem_euclid(safe_x) as usize))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn alternating_wave(dims: (usize, usize), wrap: WrappingType) -> Wave {
+    // Two states that must always differ from their neighbours, like a checkerboard.
+    let propagator = vec![vec![vec![1], vec![0]]; 4];
+    Wave::new(dims, vec![1, 1], propagator, wrap)
+}
+
+#[test]
+fn torus_neighbour_test() {
+    let wave = alternating_wave((4, 5), WrappingType::Torus);
+    assert_eq!(wave.neighbour((0, 0), 0), Some((3, 0)));
+    assert_eq!(wave.neighbour((0, 0), 3), Some((0, 4)));
+    assert_eq!(wave.neighbour((3, 4), 1), Some((3, 0)));
+    assert_eq!(wave.neighbour((3, 4), 2), Some((0, 4)));
+
+    let wave = alternating_wave((4, 5), WrappingType::NoWrap);
+    assert_eq!(wave.neighbour((0, 0), 0), None);
+    assert_eq!(wave.neighbour((3, 4), 1), None);
+    assert_eq!(wave.neighbour((3, 4), 3), Some((3, 3)));
+}
+
+#[test]
+fn propagate_test() {
+    let mut wave = alternating_wave((3, 3), WrappingType::NoWrap);
+    wave.ban((1, 1), 1);
+    assert!(wave.propagate().is_ok());
+    for ((i, j), cell) in wave.cells.indexed_iter() {
+        assert_eq!(cell.remaining, 1);
+        assert_eq!(cell.possible_states.get(0).unwrap(), (i + j) % 2 == 0);
+    }
+}
+
+#[test]
+fn undo_test() {
+    let mut wave = alternating_wave((3, 3), WrappingType::NoWrap);
+    wave.record_trail = true;
+    wave.ban((0, 0), 0);
+    assert!(wave.propagate().is_ok());
+    wave.undo_to(0);
+    let fresh = alternating_wave((3, 3), WrappingType::NoWrap);
+    for (cell, fresh_cell) in wave.cells.iter().zip(fresh.cells.iter()) {
+        assert_eq!(cell.possible_states, fresh_cell.possible_states);
+        assert_eq!(cell.remaining, fresh_cell.remaining);
+        assert_eq!(cell.compatible, fresh_cell.compatible);
+    }
+}
+
+#[test]
+fn contradiction_test() {
+    // An alternating pattern can't wrap around an odd sized torus.
+    let mut wave = alternating_wave((3, 3), WrappingType::Torus);
+    wave.ban((0, 0), 0);
+    match wave.propagate() {
+        Err(ModelError::NoValidStates(_)) => {}
+        u => panic!("Expected NoValidStates, found {:?}", u),
+    }
+}
+
+#[test]
+fn incremental_entropy_test() {
+    let weights = vec![3, 1, 2, 2];
+    let propagator = vec![vec![vec![0, 1, 2, 3]; 4]; 4];
+    let mut wave = Wave::new((2, 2), weights.clone(), propagator, WrappingType::NoWrap);
+    wave.ban((0, 0), 1);
+    wave.ban((0, 0), 2);
+    let entropy = |ws: &[usize]| {
+        let total = ws.iter().sum::<usize>() as f64;
+        -ws.iter().map(|&w| w as f64 / total).map(|p| p * p.ln()).sum::<f64>()
+    };
+    assert!((wave.cells[(0, 0)].entropy().unwrap() - entropy(&[3, 2])).abs() < 1e-9);
+    assert!((wave.cells[(1, 1)].entropy().unwrap() - entropy(&weights)).abs() < 1e-9);
+    wave.ban((0, 0), 0);
+    assert_eq!(wave.cells[(0, 0)].entropy(), Some(0.));
+}
+
+#[test]
+fn zero_weight_test() {
+    let propagator = vec![vec![vec![0, 1]; 2]; 4];
+    let mut wave = Wave::new((3, 3), vec![0, 1], propagator.clone(), WrappingType::NoWrap);
+    assert_eq!(wave.entropy((0, 0)), Some(0.));
+    wave.collapse_and_propagate(&mut seeded_rng(0)).unwrap();
+    assert!(wave.cells().iter().all(|&p| wave.possible_states(p).iter().eq(vec![false, true])));
+
+    let mut wave = Wave::new((3, 3), vec![0, 0], propagator, WrappingType::NoWrap);
+    assert_eq!(wave.entropy((0, 0)), Some(2f64.ln()));
+    wave.collapse_and_propagate(&mut seeded_rng(0)).unwrap();
+    assert!(wave.cells().iter().all(|&p| wave.remaining_states(p) == 1));
+    // Neither state is favoured when both weigh nothing.
+    for state in 0..2 {
+        assert!(wave.cells().iter().any(|&p| wave.possible_states(p)[state]));
+    }
+}
+
+/// Records what a listener is told, for tests.
+#[cfg(test)]
+#[derive(Default)]
+struct Counts {
+    chosen: usize,
+    collapsed: Vec<((usize, usize), usize)>,
+    removed: usize,
+    contradictions: usize,
+    finished: Vec<bool>,
+}
+
+#[cfg(test)]
+impl Listener for Counts {
+    fn cell_chosen(&mut self, _position: (usize, usize)) {
+        self.chosen += 1;
+    }
+    fn cell_collapsed(&mut self, position: (usize, usize), state: usize) {
+        self.collapsed.push((position, state));
+    }
+    fn state_removed(&mut self, _position: (usize, usize), _state: usize) {
+        self.removed += 1;
+    }
+    fn contradiction(&mut self, _position: (usize, usize)) {
+        self.contradictions += 1;
+    }
+    fn finished(&mut self, result: &Result<(), ModelError>) {
+        self.finished.push(result.is_ok());
+    }
+}
+
+#[test]
+fn listener_test() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let counts = Rc::new(RefCell::new(Counts::default()));
+    let mut wave = alternating_wave((3, 4), WrappingType::NoWrap);
+    wave.set_listener(Box::new(counts.clone()));
+    let mut rng = seeded_rng(2);
+    assert!(wave.collapse_and_propagate(&mut rng).is_ok());
+    {
+        let counts = counts.borrow();
+        // The first collapse decides the whole checkerboard.
+        assert_eq!(counts.chosen, 1);
+        assert_eq!(counts.collapsed.len(), 1);
+        let ((i, j), state) = counts.collapsed[0];
+        assert_eq!(wave.possible_states((i, j)).get(state), Some(true));
+        assert_eq!(counts.removed, 12);
+        assert_eq!(counts.contradictions, 0);
+        assert_eq!(counts.finished, vec![true]);
+    }
+
+    // Forcing two neighbours into the same state leaves a cell with nothing.
+    wave.reset();
+    wave.ban((0, 0), 1);
+    wave.ban((0, 1), 1);
+    assert!(wave.propagate().is_err());
+    assert_eq!(counts.borrow().contradictions, 1);
+}
+
+#[test]
+fn step_test() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Any state may sit next to any other, so every cell takes its own collapse.
+    let propagator = vec![vec![vec![0, 1]; 2]; 4];
+    let mut wave = Wave::new((2, 3), vec![1, 1], propagator, WrappingType::NoWrap);
+    let counts = Rc::new(RefCell::new(Counts::default()));
+    wave.set_listener(Box::new(counts.clone()));
+    let mut rng = seeded_rng(4);
+    let mut collapsed = Vec::new();
+    for _ in 0..6 {
+        match wave.step(&mut rng) {
+            Ok(Step::Collapsed(position, state)) => {
+                assert_eq!(wave.remaining_states(position), 1);
+                assert_eq!(wave.possible_states(position).get(state), Some(true));
+                assert!(collapsed.iter().all(|&(p, _)| p != position));
+                collapsed.push((position, state));
+            }
+            s => panic!("Expected a collapse, got {:?}", s),
+        }
+    }
+    assert_eq!(counts.borrow().collapsed, collapsed);
+    assert!(counts.borrow().finished.is_empty());
+    assert_eq!(wave.step(&mut rng).unwrap(), Step::Finished);
+    assert_eq!(wave.step(&mut rng).unwrap(), Step::Finished);
+    assert_eq!(counts.borrow().finished, vec![true, true]);
+
+    let mut wave = alternating_wave((3, 3), WrappingType::NoWrap);
+    let counts = Rc::new(RefCell::new(Counts::default()));
+    wave.set_listener(Box::new(counts.clone()));
+    wave.ban((0, 0), 1);
+    wave.ban((0, 1), 1);
+    let _ = wave.propagate();
+    match wave.step(&mut rng) {
+        Ok(Step::Contradiction(_)) => {}
+        s => panic!("Expected a contradiction, got {:?}", s),
+    }
+    assert_eq!(counts.borrow().finished, vec![false]);
+}
+
+#[test]
+fn constrain_test() {
+    let mut wave = alternating_wave((3, 3), WrappingType::NoWrap);
+    let only_first = BitVec::from_fn(2, |s| s == 0);
+    wave.constrain((0, 0), &only_first).unwrap();
+    assert_eq!(wave.remaining_states((1, 1)), 1);
+    assert!(wave.possible_states((1, 1))[0]);
+    assert!(wave.possible_states((0, 1))[1]);
+
+    // (1, 0) must now hold the second state, so pinning it to the first is impossible.
+    match wave.constrain((1, 0), &only_first) {
+        Err(ModelError::NoValidStates(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(wave.remaining_states((1, 0)), 1);
+    assert!(wave.possible_states((1, 0))[1]);
+
+    wave.reset();
+    assert_eq!(wave.constraints().len(), 1);
+    assert_eq!(wave.remaining_states((2, 2)), 1);
+    wave.clear_constraints();
+    wave.reset();
+    assert_eq!(wave.remaining_states((2, 2)), 2);
+
+    // A constraint can't be added to a wave that has already failed, and the failure sticks.
+    let mut wave = alternating_wave((3, 3), WrappingType::Torus);
+    assert!(wave.collapse_and_propagate(&mut seeded_rng(1)).is_err());
+    match wave.constrain((0, 0), &BitVec::from_elem(2, true)) {
+        Err(ModelError::NoValidStates(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(wave.constraints().is_empty());
+    assert!(wave.contradiction.is_some());
+    assert!(wave.collapse_and_propagate(&mut seeded_rng(1)).is_err());
+}